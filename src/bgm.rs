@@ -1,9 +1,16 @@
 //! Ported from https://github.com/rbirkby/picotetris/blob/master/song.cpp
+//!
+//! One loop of the theme song takes about 39 seconds at 144 BPM, see `BGM_TOTAL_DURATION_MS`
 
 const BPM: u32 = 144;
 const WHOLE_NOTE: u32 = (60000 * 4) / BPM;
 pub const SILENT_DURATION: u32 = WHOLE_NOTE / 64;
 
+/// Notes used by the background music.
+///
+/// To add a new note, append a variant at the end of the enum (before `Rest`),
+/// then add the note with its PWM setting at the same position in `FREQUENCY_TABLE`.
+/// The table is checked to be complete and sorted at compile time.
 #[derive(Copy, Clone, PartialEq)]
 pub enum Note {
     A4,   // 440 Hz,
@@ -19,60 +26,188 @@ pub enum Note {
     Rest, // 60000 Hz,
 }
 
+/// Number of variants in `Note`
+const NOTE_COUNT: usize = Note::Rest as usize + 1;
+
+#[derive(Clone, Copy)]
 pub struct Frequency {
     pub clk_div: u8,
     pub cnt: u16,
 }
 
+/// PWM setting outside of the limits of the RP2350
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrequencyError {
+    pub clk_div: u8,
+    pub cnt: u16,
+}
+
+impl Frequency {
+    /// The clock divider is in 1..=255 and the counter top in 1..=65535
+    pub const fn is_valid(&self) -> bool {
+        self.clk_div != 0 && self.cnt != 0
+    }
+
+    pub fn validate(&self) -> Result<(), FrequencyError> {
+        if self.is_valid() {
+            Ok(())
+        } else {
+            Err(FrequencyError {
+                clk_div: self.clk_div,
+                cnt: self.cnt,
+            })
+        }
+    }
+
+    /// Closest valid setting, for playing something rather than nothing
+    pub const fn clamp(&self) -> Frequency {
+        Frequency {
+            clk_div: if self.clk_div == 0 { 1 } else { self.clk_div },
+            cnt: if self.cnt == 0 { 1 } else { self.cnt },
+        }
+    }
+}
+
+/// PWM settings of each note, sorted by the discriminant of the note
+const FREQUENCY_TABLE: [(Note, Frequency); NOTE_COUNT] = [
+    (
+        Note::A4,
+        Frequency {
+            clk_div: 10,
+            cnt: 34091,
+        },
+    ),
+    (
+        Note::B4,
+        Frequency {
+            clk_div: 181,
+            cnt: 1678,
+        },
+    ),
+    (
+        Note::Gs4,
+        Frequency {
+            clk_div: 11,
+            cnt: 32835,
+        },
+    ),
+    (
+        Note::A5,
+        Frequency {
+            clk_div: 5,
+            cnt: 34091,
+        },
+    ),
+    (
+        Note::C5,
+        Frequency {
+            clk_div: 5,
+            cnt: 57334,
+        },
+    ),
+    (
+        Note::D5,
+        Frequency {
+            clk_div: 9,
+            cnt: 28377,
+        },
+    ),
+    (
+        Note::E5,
+        Frequency {
+            clk_div: 4,
+            cnt: 56883,
+        },
+    ),
+    (
+        Note::F5,
+        Frequency {
+            clk_div: 6,
+            cnt: 35793,
+        },
+    ),
+    (
+        Note::G5,
+        Frequency {
+            clk_div: 3,
+            cnt: 63776,
+        },
+    ),
+    (
+        Note::Gs5,
+        Frequency {
+            clk_div: 5,
+            cnt: 36118,
+        },
+    ),
+    (
+        Note::Rest,
+        Frequency {
+            clk_div: 1,
+            cnt: 2500,
+        },
+    ),
+];
+
+// Every note must be in the table, at the position of its discriminant, with a valid setting
+const _: () = {
+    let mut i = 0;
+    while i < NOTE_COUNT {
+        assert!(
+            FREQUENCY_TABLE[i].0 as usize == i,
+            "FREQUENCY_TABLE is not sorted"
+        );
+        assert!(
+            FREQUENCY_TABLE[i].1.is_valid(),
+            "FREQUENCY_TABLE has an invalid PWM setting"
+        );
+        i += 1;
+    }
+};
+
 impl Note {
-    pub fn frequency(&self) -> Frequency {
+    /// Position of the note in `FREQUENCY_TABLE`
+    #[inline]
+    pub const fn note_index(&self) -> usize {
+        *self as usize
+    }
+
+    /// PWM setting of the note, an index into `FREQUENCY_TABLE` as it is sorted
+    pub const fn frequency(&self) -> Frequency {
+        FREQUENCY_TABLE[self.note_index()].1
+    }
+
+    /// Pitch of the note as a MIDI note number, `None` for `Rest`
+    pub const fn semitone(&self) -> Option<u8> {
         match self {
-            Self::A4 => Frequency {
-                clk_div: 10,
-                cnt: 34091,
-            },
-            Self::B4 => Frequency {
-                clk_div: 181,
-                cnt: 1678,
-            },
-            Self::Gs4 => Frequency {
-                clk_div: 11,
-                cnt: 32835,
-            },
-            Self::C5 => Frequency {
-                clk_div: 5,
-                cnt: 57334,
-            },
-            Self::D5 => Frequency {
-                clk_div: 9,
-                cnt: 28377,
-            },
-            Self::E5 => Frequency {
-                clk_div: 4,
-                cnt: 56883,
-            },
-            Self::F5 => Frequency {
-                clk_div: 6,
-                cnt: 35793,
-            },
-            Self::G5 => Frequency {
-                clk_div: 3,
-                cnt: 63776,
-            },
-            Self::Gs5 => Frequency {
-                clk_div: 5,
-                cnt: 36118,
-            },
-            Self::A5 => Frequency {
-                clk_div: 5,
-                cnt: 34091,
-            },
-            Self::Rest => Frequency {
-                clk_div: 1,
-                cnt: 2500,
-            },
+            Self::Gs4 => Some(68),
+            Self::A4 => Some(69),
+            Self::B4 => Some(71),
+            Self::C5 => Some(72),
+            Self::D5 => Some(74),
+            Self::E5 => Some(76),
+            Self::F5 => Some(77),
+            Self::G5 => Some(79),
+            Self::Gs5 => Some(80),
+            Self::A5 => Some(81),
+            Self::Rest => None,
         }
     }
+
+    /// Note with the given pitch, if it is one of the notes in `Note`
+    fn from_semitone(semitone: u8) -> Option<Self> {
+        FREQUENCY_TABLE
+            .iter()
+            .map(|(note, _)| *note)
+            .find(|note| note.semitone() == Some(semitone))
+    }
+
+    /// Note the given number of semitones above (or below when negative) this one,
+    /// `None` when there is no such note in `Note`
+    pub fn transpose(&self, semitones: i8) -> Option<Self> {
+        let semitone = self.semitone()?.checked_add_signed(semitones)?;
+        Self::from_semitone(semitone)
+    }
 }
 
 use Note::*;
@@ -180,21 +315,191 @@ const TETRIS_BGM: &[(Note, u32, bool)] = &[
     (Gs5, 2, false),
 ];
 
+/// Number of notes in one loop of the theme song
+pub const BGM_NOTE_COUNT: usize = TETRIS_BGM.len();
+
+/// Time in milliseconds from the start of the theme song at which each of its notes ends
+const CUMULATIVE_DURATIONS: [u32; BGM_NOTE_COUNT] = {
+    let mut ends = [0; BGM_NOTE_COUNT];
+    let mut total: u64 = 0;
+    let mut i = 0;
+
+    while i < TETRIS_BGM.len() {
+        let (_, divider, dotted) = TETRIS_BGM[i];
+        total += note_duration(divider, dotted) as u64;
+        assert!(total < u32::MAX as u64, "the theme song is too long");
+        ends[i] = total as u32;
+        i += 1;
+    }
+
+    ends
+};
+
+/// Duration of one loop of the theme song in milliseconds
+pub const BGM_TOTAL_DURATION_MS: u32 = CUMULATIVE_DURATIONS[BGM_NOTE_COUNT - 1];
+
+/// Note of the theme song playing at the given time from its start, the song is looped
+pub fn note_at_ms(elapsed_ms: u32) -> Note {
+    let elapsed_ms = elapsed_ms % BGM_TOTAL_DURATION_MS;
+    let index = CUMULATIVE_DURATIONS.partition_point(|&end| end <= elapsed_ms);
+    TETRIS_BGM[index].0
+}
+
+/// Evaluated at compile time, every note of the theme song must have a frequency
+/// and a non-zero divider
+const fn validate_bgm() {
+    let mut i = 0;
+
+    while i < TETRIS_BGM.len() {
+        let (note, divider, _) = TETRIS_BGM[i];
+        let _ = note.frequency();
+        assert!(divider > 0, "a note of the theme song has no duration");
+        i += 1;
+    }
+}
+
+const _: () = validate_bgm();
+
+/// Duration of a note in milliseconds for the given divider of the whole note
+const fn note_duration(divider: u32, dotted: bool) -> u32 {
+    let mut duration = WHOLE_NOTE / divider;
+
+    if dotted {
+        // dotted notes are 1.5x the duration of a regular note
+        // so 4-dotted notes in the song is roughly equivalent to divider of 2.67 regular notes
+        duration *= 3;
+        duration /= 2;
+    }
+
+    duration
+}
+
+/// Every level plays the music this many percent faster, up to `MAX_TEMPO_PERCENT`
+const TEMPO_STEP_PERCENT: u32 = 5;
+const MAX_TEMPO_PERCENT: u32 = 160;
+
+/// Duration of a note of the theme song played at the given level of the game,
+/// never shorter than the silence between the notes
+pub fn duration_at_level(duration: u32, level: u8) -> u32 {
+    let tempo = 100 + TEMPO_STEP_PERCENT * (level.max(1) as u32 - 1);
+    (duration * 100 / tempo.min(MAX_TEMPO_PERCENT)).max(SILENT_DURATION)
+}
+
 /// Returns an infinite iterator over the notes and its duration of the Tetris theme song.
 pub fn melody() -> impl Iterator<Item = (Note, u32)> {
     TETRIS_BGM
-        .into_iter()
-        .map(|(note, divider, dotted)| {
-            let mut duration = WHOLE_NOTE / divider;
-
-            if *dotted {
-                // dotted notes are 1.5x the duration of a regular note
-                // so 4-dotted notes in the song is roughly equivalent to divider of 2.67 regular notes
-                duration *= 3;
-                duration /= 2;
-            }
-
-            (*note, duration)
-        })
+        .iter()
+        .map(|&(note, divider, dotted)| (note, note_duration(divider, dotted)))
         .cycle()
 }
+
+/// Short sounds played over the melody on their own PWM slice
+#[derive(Copy, Clone, PartialEq)]
+pub enum Effect {
+    LineClear,
+    GameOver,
+}
+
+impl Effect {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::LineClear),
+            1 => Some(Self::GameOver),
+            _ => None,
+        }
+    }
+
+    /// Note of the effect at the given position with its duration in milliseconds.
+    /// The effects are arpeggios, given by the semitones from their first note
+    pub fn note(&self, index: usize) -> Option<(Note, u32)> {
+        let (root, intervals, duration): (Note, &[i8], u32) = match self {
+            // Ascending C major
+            Self::LineClear => (C5, &[0, 4, 7], 60),
+            // Descending A minor
+            Self::GameOver => (E5, &[0, -4, -7], 150),
+        };
+
+        let note = root.transpose(*intervals.get(index)?)?;
+        Some((note, duration))
+    }
+}
+
+/// Returns the melody together with the position inside the current loop of the song.
+pub fn melody_with_progress() -> MelodyProgress<impl Iterator<Item = (Note, u32)>> {
+    MelodyProgress {
+        inner: melody(),
+        pos: 0,
+    }
+}
+
+pub struct MelodyProgress<I> {
+    inner: I,
+    pos: usize,
+}
+
+impl<I> MelodyProgress<I> {
+    /// How far the song is in its current loop, in range of 0..=100
+    pub fn progress_percent(&self) -> u8 {
+        (self.pos * 100 / BGM_NOTE_COUNT) as u8
+    }
+}
+
+impl<I: Iterator<Item = (Note, u32)>> Iterator for MelodyProgress<I> {
+    type Item = (Note, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next()?;
+        self.pos = (self.pos + 1) % BGM_NOTE_COUNT;
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_at_ms_follows_the_start_of_the_theme_song() {
+        let expected = [
+            (0, E5),
+            (415, E5),
+            (416, B4),
+            (624, C5),
+            (1000, D5),
+            (1248, C5),
+            (1663, B4),
+            (2080, A4),
+            (2500, E5),
+            (3951, B4),
+        ];
+
+        for (elapsed_ms, note) in expected {
+            assert!(
+                note_at_ms(elapsed_ms) == note,
+                "wrong note at {elapsed_ms}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn note_at_ms_loops_the_theme_song() {
+        assert!(note_at_ms(BGM_TOTAL_DURATION_MS - 1) == Gs5);
+        assert!(note_at_ms(BGM_TOTAL_DURATION_MS) == E5);
+        assert!(note_at_ms(BGM_TOTAL_DURATION_MS + 416) == B4);
+    }
+
+    #[test]
+    fn transposing_by_an_octave() {
+        assert!(A4.transpose(12) == Some(A5));
+        assert!(A5.transpose(-12) == Some(A4));
+        assert!(Gs4.transpose(12) == Some(Gs5));
+    }
+
+    #[test]
+    fn transposing_out_of_the_notes_gives_none() {
+        // A#4 is not one of the notes
+        assert!(A4.transpose(1).is_none());
+        assert!(A5.transpose(1).is_none());
+        assert!(Rest.transpose(0).is_none());
+    }
+}
@@ -1,3 +1,5 @@
+use display_interface::DisplayError;
+use embedded_hal::delay::DelayNs;
 use embedded_hal::i2c::I2c;
 use heapless::String;
 use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306};
@@ -6,41 +8,206 @@ use embedded_graphics::{
     image::{Image, ImageRaw},
     mono_font::{
         ascii::{FONT_5X8, FONT_6X10},
-        MonoTextStyle,
+        MonoTextStyle, MonoTextStyleBuilder,
     },
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
-    text::{Alignment, Text},
+    primitives::{PrimitiveStyleBuilder, Rectangle, StrokeAlignment},
+    text::{Alignment, Baseline, Text},
 };
 
 use core::fmt::Write as _;
+use rand::RngCore;
+
+use crate::tetris::{self, Board, Cell, Coordination, GameMode, Rotation, Tetris, Tetromino};
 
 const BOARD_OFFSET_X: i16 = 8;
 const BOARD_OFFSET_Y: i16 = 26;
 const NEXT_PIECE_OFFSET_X: i16 = 42;
 const NEXT_PIECE_OFFSET_Y: i16 = 10;
+const LINE_CLEAR_BLINKS: u8 = 3;
+const LINE_CLEAR_BLINK_MS: u32 = 50;
+/// The new level is shown below the combo
+const LEVEL_UP_TEXT_Y: i32 = 74;
+/// Size of the next piece panel in cells
+const NEXT_PIECE_AREA: i16 = 4;
+/// The label of a preview slot is in the gap between the score and the next piece
+const PREVIEW_LABEL_X: i32 = NEXT_PIECE_OFFSET_X as i32 - 6;
+/// The held piece is drawn small above the next piece, next to the score
+const HOLD_PIECE_OFFSET_Y: i16 = 1;
+const HOLD_CELL_SIZE: i16 = 2;
+const PROGRESS_BAR_Y: i32 = 127;
+/// Size of the display after rotating it to the portrait orientation
+const DISPLAY_WIDTH: i16 = 64;
+const PROGRESS_BAR_WIDTH: u32 = 64;
+/// The danger bar is in the space between the left edge of the display and the board
+const DANGER_BAR_X: i32 = 2;
+const DANGER_BAR_WIDTH: u32 = 3;
+/// Number of digits of the highest possible score
+const SCORE_DIGITS: usize = u64::MAX.ilog10() as usize + 1;
+/// The score with a comma between the groups of 3 digits
+const SCORE_TEXT_LEN: usize = SCORE_DIGITS + (SCORE_DIGITS - 1) / 3;
+/// `Score` above the score
+const SCORE_LABEL_LEN: usize = "Score\n".len() + SCORE_TEXT_LEN;
+const LEVEL_TEXT_LEN: usize = "Level ".len() + u32::MAX.ilog10() as usize + 1;
+const LINES_TEXT_LEN: usize = "Lines\n".len() + u32::MAX.ilog10() as usize + 1;
+/// `Time` above the time as minutes, seconds and milliseconds
+const TIME_TEXT_LEN: usize =
+    "Time\n".len() + (u64::MAX / 60_000).ilog10() as usize + 1 + ":00.000".len();
+const MODE_TEXT_LEN: usize = "Sprint ".len() + u32::MAX.ilog10() as usize + 1;
+const COMBO_TEXT_LEN: usize = i32::MAX.ilog10() as usize + 1 + "x COMBO!".len();
+/// Bottom rows of the final board shown on the game over screen
+const GAME_OVER_ROWS: usize = 5;
+/// Size of a cell of the final board on the game over screen in pixels
+const GAME_OVER_CELL_SIZE: i16 = 2;
+/// Top of the final board on the game over screen, above the score
+const GAME_OVER_BOARD_Y: i16 = 36;
+/// Baseline of the score under its label on the game over screen
+const GAME_OVER_SCORE_Y: i32 = 70;
+/// Characters of `FONT_6X10` which fit on a line of the display
+const LINE_CHARS: usize = DISPLAY_WIDTH as usize / 6;
+const SCORE_SCROLL_STEP: i32 = 2;
+const SCORE_SCROLL_DELAY_NS: u32 = 20_000_000;
+const DISPLAY_HEIGHT: i16 = 128;
+const MATRIX_RAIN_DROPS: usize = 10;
+/// Length of the lit trail behind a falling drop of the screensaver
+const MATRIX_RAIN_TAIL: u8 = 8;
+
+/// Largest size of a cell in pixels for which a board with the given number of columns and rows
+/// fits on the display together with its border, above the progress bar
+pub const fn board_size_mul(columns: usize, rows: usize) -> i16 {
+    let by_width = (DISPLAY_WIDTH - BOARD_OFFSET_X - 1) / columns as i16;
+    let by_height = (PROGRESS_BAR_Y as i16 - BOARD_OFFSET_Y - 1) / rows as i16;
+
+    if by_width < by_height {
+        by_width
+    } else {
+        by_height
+    }
+}
+
+/// The board of the given size in pixels fits on the display together with its border,
+/// above the progress bar
+pub const fn board_fits(width: i16, height: i16) -> bool {
+    BOARD_OFFSET_X + width + 1 <= DISPLAY_WIDTH
+        && BOARD_OFFSET_Y + height + 1 <= PROGRESS_BAR_Y as i16
+}
+
+/// Look and position of the board on the display
+#[derive(Clone, Copy)]
+pub struct BoardStyle {
+    pub border_width: u32,
+    pub fill: BinaryColor,
+    pub border_color: BinaryColor,
+    /// Position of the top left cell, the border is drawn around it
+    pub offset_x: i16,
+    pub offset_y: i16,
+}
+
+impl Default for BoardStyle {
+    fn default() -> Self {
+        Self {
+            border_width: 1,
+            fill: BinaryColor::Off,
+            border_color: BinaryColor::On,
+            offset_x: BOARD_OFFSET_X,
+            offset_y: BOARD_OFFSET_Y,
+        }
+    }
+}
+
+impl BoardStyle {
+    /// Top left pixel of the cell at the given column and row
+    fn cell_origin(&self, dx: i16, dy: i16, size_mul: i16) -> Point {
+        Point::new(
+            (dx * size_mul + self.offset_x) as i32,
+            (dy * size_mul + self.offset_y) as i32,
+        )
+    }
+}
 
 pub struct Display<I2C, const SIZE_MUL: i16> {
     handle: Ssd1306<I2CInterface<I2C>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
+    /// The buffer has changes which are not flushed to the display yet
+    dirty: bool,
+    style: BoardStyle,
+    /// Column and row of the head of every drop of the screensaver
+    rain: heapless::Vec<(u8, u8), MATRIX_RAIN_DROPS>,
+    /// Size of a cell in pixels, only read when `SIZE_MUL` is 0 for a display made by
+    /// `Display::new_dynamic`
+    size_mul: i16,
 }
 
 impl<I2C: I2c, const SIZE_MUL: i16> Display<I2C, SIZE_MUL> {
+    /// Evaluated at compile time when the display is initialized
+    const SIZE_MUL_CHECK: () = assert!(
+        matches!(SIZE_MUL, 1 | 5..=10),
+        "SIZE_MUL must be 1 for a pixel per cell, or between 5 to fit the cell patterns and 10 to fit the board"
+    );
+
     pub fn init(i2c: I2C) -> Self {
+        let () = Self::SIZE_MUL_CHECK;
+        Self::with_size_mul(i2c, SIZE_MUL)
+    }
+
+    fn with_size_mul(i2c: I2C, size_mul: i16) -> Self {
         let interface = ssd1306::I2CDisplayInterface::new(i2c);
         let mut handle = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate270)
             .into_buffered_graphics_mode();
 
         handle.init().unwrap();
 
-        Self { handle }
+        Self {
+            handle,
+            dirty: false,
+            style: BoardStyle::default(),
+            rain: heapless::Vec::new(),
+            size_mul,
+        }
     }
 
-    pub fn flush(&mut self) {
-        self.handle.flush().unwrap();
+    /// Size of a cell in pixels, known at compile time unless the display is dynamic
+    #[inline]
+    fn effective_size_mul(&self) -> i16 {
+        if SIZE_MUL == 0 {
+            self.size_mul
+        } else {
+            SIZE_MUL
+        }
+    }
+
+    /// Initializes the display again, used to recover it after a bus error
+    pub fn reinit(&mut self) -> Result<(), DisplayError> {
+        self.handle.init()?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), DisplayError> {
+        self.handle.flush()?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Starts drawing a new frame from an empty buffer,
+    /// should be called once before every full redraw
+    pub fn begin_frame(&mut self) {
+        self.handle.clear_buffer();
+        self.rain.clear();
+        // the cleared buffer has to be sent to the display as well
+        self.dirty = true;
     }
 
-    pub fn draw_start_screen(&mut self) {
+    /// Sends the frame to the display if anything has been drawn since the last flush
+    pub fn end_frame(&mut self) -> Result<(), DisplayError> {
+        if self.dirty {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn draw_start_screen(&mut self) -> Result<(), DisplayError> {
         let raw: ImageRaw<BinaryColor> = ImageRaw::new(include_bytes!("../logo.raw"), 64);
 
         let im = Image::new(&raw, Point::new(0, 0));
@@ -52,30 +219,124 @@ impl<I2C: I2c, const SIZE_MUL: i16> Display<I2C, SIZE_MUL> {
             Alignment::Center,
         );
 
-        im.draw(&mut self.handle).unwrap();
-        welcome.draw(&mut self.handle).unwrap();
-        self.flush();
+        im.draw(&mut self.handle)?;
+        welcome.draw(&mut self.handle)?;
+        self.dirty = true;
+        Ok(())
     }
 
-    pub fn draw_board(&mut self, width: i16, height: i16) {
-        self.handle.clear_buffer();
+    /// Moves every drop of the screensaver one pixel down, a drop leaving the bottom
+    /// starts again at the top of a random column. The screen is cleared on the first frame
+    pub fn draw_matrix_rain_frame(&mut self, rng: &mut impl RngCore) -> Result<(), DisplayError> {
+        if self.rain.is_empty() {
+            self.handle.clear_buffer();
+        }
 
-        let style = PrimitiveStyleBuilder::new()
-            .stroke_color(BinaryColor::On)
-            .stroke_width(1)
-            .fill_color(BinaryColor::Off)
+        if !self.rain.is_full() {
+            let _ = self.rain.push((random_column(rng), 0));
+        }
+
+        for drop in self.rain.iter_mut() {
+            let (column, row) = *drop;
+
+            Pixel(Point::new(column as i32, row as i32), BinaryColor::On).draw(&mut self.handle)?;
+
+            if let Some(tail) = row.checked_sub(MATRIX_RAIN_TAIL) {
+                Pixel(Point::new(column as i32, tail as i32), BinaryColor::Off)
+                    .draw(&mut self.handle)?;
+            }
+
+            *drop = if row as i16 >= DISPLAY_HEIGHT + MATRIX_RAIN_TAIL as i16 {
+                (random_column(rng), 0)
+            } else {
+                (column, row + 1)
+            };
+        }
+
+        self.dirty = true;
+        self.end_frame()
+    }
+
+    /// Marks the start screen when the next game is going to play itself
+    #[cfg(feature = "ai")]
+    pub fn draw_auto_play(&mut self) -> Result<(), DisplayError> {
+        Text::with_alignment(
+            "Auto",
+            Point::new(32, 126),
+            MonoTextStyle::new(&FONT_5X8, BinaryColor::On),
+            Alignment::Center,
+        )
+        .draw(&mut self.handle)?;
+
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Names the mode the next game is started in on the start screen,
+    /// nothing for the marathon
+    pub fn draw_game_mode(&mut self, mode: GameMode) -> Result<(), DisplayError> {
+        let GameMode::Sprint { lines_goal } = mode else {
+            return Ok(());
+        };
+
+        let mut text: String<MODE_TEXT_LEN> = String::new();
+        write!(&mut text, "Sprint {}", lines_goal).unwrap();
+
+        Text::with_alignment(
+            &text,
+            Point::new(32, 116),
+            MonoTextStyle::new(&FONT_5X8, BinaryColor::On),
+            Alignment::Center,
+        )
+        .draw(&mut self.handle)?;
+
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Draws the border of the board of the given game
+    pub fn draw_board_auto<const C: usize, const R: usize, Rng: RngCore>(
+        &mut self,
+        _tetris: &Tetris<C, R, Rng>,
+    ) -> Result<(), DisplayError> {
+        self.draw_board_for::<C, R>()
+    }
+
+    /// Style used for drawing the board from now on, takes effect on the next full redraw
+    pub fn set_style(&mut self, style: BoardStyle) {
+        self.style = style;
+    }
+
+    /// Draws the border of a board with `C` columns and `R` rows in the current style,
+    /// it fits on the display when `SIZE_MUL` is at most `board_size_mul(C, R)`
+    pub fn draw_board_for<const C: usize, const R: usize>(&mut self) -> Result<(), DisplayError> {
+        let style = self.style;
+        self.draw_board::<C, R>(&style)
+    }
+
+    /// Draws the border of a board with `C` columns and `R` rows in the given style,
+    /// a board with cells of a single pixel has no border
+    pub fn draw_board<const C: usize, const R: usize>(
+        &mut self,
+        style: &BoardStyle,
+    ) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        let size_mul = self.effective_size_mul();
+        let border_width = if size_mul < 2 { 0 } else { style.border_width };
+        let primitive_style = PrimitiveStyleBuilder::new()
+            .stroke_color(style.border_color)
+            .stroke_width(border_width)
+            .stroke_alignment(StrokeAlignment::Outside)
+            .fill_color(style.fill)
             .build();
 
         Rectangle::new(
-            Point::new(BOARD_OFFSET_X as i32 - 1, BOARD_OFFSET_Y as i32 - 1),
-            Size::new(
-                (width * SIZE_MUL) as u32 + 2,
-                (height * SIZE_MUL) as u32 + 2,
-            ),
+            style.cell_origin(0, 0, size_mul),
+            Size::new((C as i16 * size_mul) as u32, (R as i16 * size_mul) as u32),
         )
-        .into_styled(style)
-        .draw(&mut self.handle)
-        .unwrap();
+        .into_styled(primitive_style)
+        .draw(&mut self.handle)?;
 
         Text::with_alignment(
             "Next",
@@ -83,17 +344,64 @@ impl<I2C: I2c, const SIZE_MUL: i16> Display<I2C, SIZE_MUL> {
             MonoTextStyle::new(&FONT_5X8, BinaryColor::On),
             Alignment::Left,
         )
-        .draw(&mut self.handle)
-        .unwrap();
+        .draw(&mut self.handle)?;
+
+        Ok(())
     }
 
-    pub fn draw_piece(&mut self, dx: i16, dy: i16, on: bool) {
+    /// Draws all the cells in a single pass over the display buffer
+    pub fn draw_pieces_batch(&mut self, blocks: &[(i16, i16, Cell)]) -> Result<(), DisplayError> {
+        self.dirty = true;
+        let style = self.style;
+        let size_mul = self.effective_size_mul();
+
+        let pixels = blocks.iter().flat_map(|&(dx, dy, cell)| {
+            Self::cell_pixels(style.cell_origin(dx, dy, size_mul), cell, size_mul)
+        });
+
+        self.handle.draw_iter(pixels)?;
+
+        // The ghost cells have been cleared above, their outline goes over them
+        for &(dx, dy, cell) in blocks {
+            if cell == Cell::Ghost {
+                self.draw_ghost_piece(dx, dy)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Outline of a block of the ghost piece, the inside of the cell is cleared.
+    /// A cell of a single pixel has no room for the outline, the ghost is not shown there
+    pub fn draw_ghost_piece(&mut self, dx: i16, dy: i16) -> Result<(), DisplayError> {
+        self.fill_cell(dx, dy, false)?;
+
+        let size_mul = self.effective_size_mul();
+        if size_mul < 2 {
+            return Ok(());
+        }
+
         let block = Rectangle::new(
-            Point::new(
-                (dx * SIZE_MUL + BOARD_OFFSET_X) as i32,
-                (dy * SIZE_MUL + BOARD_OFFSET_Y) as i32,
-            ),
-            Size::new(SIZE_MUL as u32, SIZE_MUL as u32),
+            self.style.cell_origin(dx, dy, size_mul),
+            Size::new(size_mul as u32, size_mul as u32),
+        );
+
+        let style = PrimitiveStyleBuilder::new()
+            .stroke_color(BinaryColor::On)
+            .stroke_width(1)
+            .stroke_alignment(StrokeAlignment::Inside)
+            .build();
+
+        block.into_styled(style).draw(&mut self.handle)
+    }
+
+    fn fill_cell(&mut self, dx: i16, dy: i16, on: bool) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        let size_mul = self.effective_size_mul();
+        let block = Rectangle::new(
+            self.style.cell_origin(dx, dy, size_mul),
+            Size::new(size_mul as u32, size_mul as u32),
         );
 
         let style = PrimitiveStyleBuilder::new()
@@ -104,30 +412,222 @@ impl<I2C: I2c, const SIZE_MUL: i16> Display<I2C, SIZE_MUL> {
             })
             .build();
 
-        block.into_styled(style).draw(&mut self.handle).unwrap();
+        block.into_styled(style).draw(&mut self.handle)
     }
 
-    pub fn draw_next_piece(&mut self, dx: i16, dy: i16) {
-        Rectangle::new(
-            Point::new(
-                (dx * SIZE_MUL + NEXT_PIECE_OFFSET_X) as i32,
-                (dy * SIZE_MUL + NEXT_PIECE_OFFSET_Y) as i32,
-            ),
-            Size::new(SIZE_MUL as u32, SIZE_MUL as u32),
+    /// Pixels of the cell at the given top left pixel with the 5x5 pattern of its piece
+    /// stretched over it, all of them are off for an empty cell. A cell of a single pixel
+    /// is lit for any piece, as the pattern cannot be shrunk to it
+    fn cell_pixels(
+        origin: Point,
+        cell: Cell,
+        size_mul: i16,
+    ) -> impl Iterator<Item = Pixel<BinaryColor>> {
+        let pattern = match cell {
+            Cell::Empty | Cell::Ghost => [[false; 5]; 5],
+            Cell::Occupied(_) if size_mul < 2 => [[true; 5]; 5],
+            Cell::Occupied(t) => Cell::render_pattern(t),
+        };
+
+        (0..size_mul).flat_map(move |y| {
+            (0..size_mul).map(move |x| {
+                let on = pattern[(y * 5 / size_mul) as usize][(x * 5 / size_mul) as usize];
+
+                Pixel(
+                    origin + Point::new(x as i32, y as i32),
+                    if on {
+                        BinaryColor::On
+                    } else {
+                        BinaryColor::Off
+                    },
+                )
+            })
+        })
+    }
+
+    /// Blinks the given rows of the board before they are removed
+    pub fn animate_line_clear(
+        &mut self,
+        rows: &[usize],
+        width: i16,
+        delay: &mut impl DelayNs,
+    ) -> Result<(), DisplayError> {
+        for _ in 0..LINE_CLEAR_BLINKS {
+            for on in [false, true] {
+                for &row in rows {
+                    for x in 0..width {
+                        self.fill_cell(x, row as i16, on)?;
+                    }
+                }
+
+                self.flush()?;
+                delay.delay_ms(LINE_CLEAR_BLINK_MS);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fills the whole display for a step of the level up flash, lit or dark
+    pub fn draw_level_up_flash(&mut self, lit: bool) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        let area = self.handle.bounding_box();
+        DrawTarget::fill_solid(&mut self.handle, &area, BinaryColor::from(lit))
+    }
+
+    /// Shows the level the game has just reached over the board
+    pub fn draw_level_up(&mut self, new_level: u32) -> Result<(), DisplayError> {
+        let mut text: String<LEVEL_TEXT_LEN> = String::new();
+        write!(&mut text, "Level {}", new_level).unwrap();
+
+        self.draw_overlay_text(&text, LEVEL_UP_TEXT_Y)
+    }
+
+    /// Shows the number of line clears in a row over the center of the board,
+    /// `combo` counts them after the first one like the game does
+    pub fn draw_combo(&mut self, combo: i32) -> Result<(), DisplayError> {
+        let mut text: String<COMBO_TEXT_LEN> = String::new();
+        write!(&mut text, "{}x COMBO!", combo.saturating_add(1)).unwrap();
+
+        self.draw_overlay_text(&text, 64)
+    }
+
+    /// Draws a short text horizontally centered at the given baseline, over whatever
+    /// is drawn there
+    pub fn draw_overlay_text(&mut self, text: &str, y: i32) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        let style = MonoTextStyleBuilder::new()
+            .font(&FONT_5X8)
+            .text_color(BinaryColor::On)
+            .background_color(BinaryColor::Off)
+            .build();
+
+        Text::with_alignment(
+            text,
+            Point::new(DISPLAY_WIDTH as i32 / 2, y),
+            style,
+            Alignment::Center,
         )
-        .into_styled(
-            PrimitiveStyleBuilder::new()
-                .fill_color(BinaryColor::On)
-                .build(),
+        .draw(&mut self.handle)?;
+
+        Ok(())
+    }
+
+    /// Draws the piece in its flattest rotation, centered inside the next piece panel
+    pub fn draw_next_piece(&mut self, piece: Tetromino) -> Result<(), DisplayError> {
+        self.draw_preview_slot(0, piece, preview_rotation(piece))
+    }
+
+    /// Draws a piece of the queue in its slot below the next piece, labelled with `N`
+    /// for the next piece and with its position in the queue for the later ones
+    pub fn draw_preview_slot(
+        &mut self,
+        slot_index: usize,
+        piece: Tetromino,
+        rotation: Rotation,
+    ) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        let size_mul = self.effective_size_mul();
+        // The slots are as tall as the next piece panel
+        let slot_y = NEXT_PIECE_OFFSET_Y + slot_index as i16 * NEXT_PIECE_AREA * size_mul;
+        let center = self.center_offset_for_piece(piece, rotation);
+
+        let mut label: String<2> = String::new();
+        if slot_index == 0 {
+            let _ = label.push('N');
+        } else {
+            let _ = write!(&mut label, "{}", slot_index + 1);
+        }
+
+        Text::with_baseline(
+            &label,
+            Point::new(PREVIEW_LABEL_X, slot_y as i32),
+            MonoTextStyle::new(&FONT_5X8, BinaryColor::On),
+            Baseline::Top,
         )
-        .draw(&mut self.handle)
-        .unwrap();
+        .draw(&mut self.handle)?;
+
+        for block in tetris::get_tetromino_blocks(piece, rotation) {
+            Rectangle::new(
+                Point::new(
+                    (block.x * size_mul + center.x + NEXT_PIECE_OFFSET_X) as i32,
+                    (block.y * size_mul + center.y + slot_y) as i32,
+                ),
+                Size::new(size_mul as u32, size_mul as u32),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(BinaryColor::On)
+                    .build(),
+            )
+            .draw(&mut self.handle)?;
+        }
+
+        Ok(())
     }
 
-    pub fn draw_score(&mut self, score: u64) {
-        let mut score_fmt: String<11> = String::new();
+    /// Draws the held piece in the hold panel, labelled with `H`. Once it has been used for
+    /// the current piece, only a pixel of each of its blocks is lit
+    pub fn draw_hold_piece(
+        &mut self,
+        piece: Option<Tetromino>,
+        used: bool,
+    ) -> Result<(), DisplayError> {
+        self.dirty = true;
 
-        write!(&mut score_fmt, "Score\n{}", score).unwrap();
+        Text::with_baseline(
+            "H",
+            Point::new(PREVIEW_LABEL_X, HOLD_PIECE_OFFSET_Y as i32),
+            MonoTextStyle::new(&FONT_5X8, BinaryColor::On),
+            Baseline::Top,
+        )
+        .draw(&mut self.handle)?;
+
+        let Some(piece) = piece else {
+            return Ok(());
+        };
+
+        let rotation = preview_rotation(piece);
+        let center = center_offset_in_panel(piece, rotation, HOLD_CELL_SIZE);
+        // Centered above the next piece panel
+        let panel_x = NEXT_PIECE_OFFSET_X
+            + (self.effective_size_mul() - HOLD_CELL_SIZE) * NEXT_PIECE_AREA / 2;
+        let block_size = if used { 1 } else { HOLD_CELL_SIZE as u32 };
+
+        for block in tetris::get_tetromino_blocks(piece, rotation) {
+            Rectangle::new(
+                Point::new(
+                    (block.x * HOLD_CELL_SIZE + center.x + panel_x) as i32,
+                    (block.y * HOLD_CELL_SIZE + center.y + HOLD_PIECE_OFFSET_Y) as i32,
+                ),
+                Size::new(block_size, block_size),
+            )
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(BinaryColor::On)
+                    .build(),
+            )
+            .draw(&mut self.handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Offset in pixels which centers the piece inside the `NEXT_PIECE_AREA` cells of the panel
+    fn center_offset_for_piece(&self, piece: Tetromino, rotation: Rotation) -> Coordination {
+        center_offset_in_panel(piece, rotation, self.effective_size_mul())
+    }
+
+    pub fn draw_score(&mut self, score: u64) -> Result<(), DisplayError> {
+        self.dirty = true;
+        let mut score_fmt: String<SCORE_LABEL_LEN> = String::new();
+        let mut score_str = String::new();
+        format_score(score, &mut score_str);
+
+        write!(&mut score_fmt, "Score\n{}", score_str).unwrap();
 
         Text::with_alignment(
             &*score_fmt,
@@ -135,25 +635,390 @@ impl<I2C: I2c, const SIZE_MUL: i16> Display<I2C, SIZE_MUL> {
             MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
             Alignment::Center,
         )
-        .draw(&mut self.handle)
-        .unwrap();
+        .draw(&mut self.handle)?;
+
+        Ok(())
     }
 
-    pub fn draw_game_over(&mut self, score: u64) {
-        self.handle.clear_buffer();
+    /// Lines left to clear in the sprint mode, drawn in place of the score
+    pub fn draw_lines_remaining(&mut self, lines: u32) -> Result<(), DisplayError> {
+        self.dirty = true;
+        let mut text: String<LINES_TEXT_LEN> = String::new();
+        write!(&mut text, "Lines\n{}", lines).unwrap();
 
-        let mut score_fmt: String<20> = String::new();
+        Text::with_alignment(
+            &text,
+            Point::new(20, 8),
+            MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+            Alignment::Center,
+        )
+        .draw(&mut self.handle)?;
+
+        Ok(())
+    }
 
-        write!(&mut score_fmt, "Score\n{}", score).unwrap();
+    /// Time of a sprint as `MM:SS.mmm`, drawn where the game over screen shows the score
+    pub fn draw_elapsed_time(&mut self, ms: u64) -> Result<(), DisplayError> {
+        self.dirty = true;
+        let mut text: String<TIME_TEXT_LEN> = String::new();
+        write!(
+            &mut text,
+            "Time\n{:02}:{:02}.{:03}",
+            ms / 60_000,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+        .unwrap();
 
-        let score = Text::with_alignment(
-            &*score_fmt,
+        Text::with_alignment(
+            &text,
+            Point::new(32, 60),
+            MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+            Alignment::Center,
+        )
+        .draw(&mut self.handle)?;
+
+        Ok(())
+    }
+
+    /// Sprint which was topped out before clearing all of its lines
+    fn draw_not_finished(&mut self) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        Text::with_alignment(
+            "Time\nDNF",
             Point::new(32, 60),
             MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
             Alignment::Center,
+        )
+        .draw(&mut self.handle)?;
+
+        Ok(())
+    }
+
+    /// Draws a bar along the left side of the board for `R` rows when the stack is in danger,
+    /// clears it otherwise
+    pub fn draw_danger_bar<const R: usize>(&mut self, danger: bool) -> Result<(), DisplayError> {
+        self.dirty = true;
+
+        Rectangle::new(
+            Point::new(DANGER_BAR_X, self.style.offset_y as i32),
+            Size::new(
+                DANGER_BAR_WIDTH,
+                (R as i16 * self.effective_size_mul()) as u32,
+            ),
+        )
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(if danger {
+                    BinaryColor::On
+                } else {
+                    BinaryColor::Off
+                })
+                .build(),
+        )
+        .draw(&mut self.handle)
+    }
+
+    /// Draws a one pixel tall bar at the bottom of the screen, `percent` is in range of 0..=100
+    pub fn draw_progress(&mut self, percent: u8) -> Result<(), DisplayError> {
+        self.dirty = true;
+        let filled = PROGRESS_BAR_WIDTH * percent.min(100) as u32 / 100;
+
+        Rectangle::new(
+            Point::new(0, PROGRESS_BAR_Y),
+            Size::new(PROGRESS_BAR_WIDTH, 1),
+        )
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(BinaryColor::Off)
+                .build(),
+        )
+        .draw(&mut self.handle)?;
+
+        Rectangle::new(Point::new(0, PROGRESS_BAR_Y), Size::new(filled, 1))
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(BinaryColor::On)
+                    .build(),
+            )
+            .draw(&mut self.handle)
+    }
+
+    /// Scrolls the line of text from the right edge of the display to the left by `step` pixels
+    /// every `delay_ns`, until it has left the display. `y` is the baseline of the text
+    pub fn scroll_text(
+        &mut self,
+        text: &str,
+        y: i32,
+        step: i32,
+        delay_ns: u32,
+        timer: &mut impl DelayNs,
+    ) -> Result<(), DisplayError> {
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        let clear_style = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::Off)
+            .build();
+        let step = step.max(1);
+        let mut offset = DISPLAY_WIDTH as i32;
+
+        loop {
+            let line = Text::new(text, Point::new(offset, y), style);
+            line.draw(&mut self.handle)?;
+            self.dirty = true;
+            self.flush()?;
+
+            timer.delay_ns(delay_ns);
+
+            let bounding_box = line.bounding_box();
+            bounding_box
+                .into_styled(clear_style)
+                .draw(&mut self.handle)?;
+
+            offset -= step;
+
+            if offset + bounding_box.size.width as i32 <= 0 {
+                break;
+            }
+        }
+
+        self.flush()
+    }
+
+    /// The score of the game, or in the sprint mode its time, `DNF` when it was not finished
+    pub fn draw_game_over(
+        &mut self,
+        score: u64,
+        mode: GameMode,
+        time_ms: Option<u64>,
+    ) -> Result<(), DisplayError> {
+        if let GameMode::Sprint { .. } = mode {
+            return match time_ms {
+                Some(ms) => self.draw_elapsed_time(ms),
+                None => self.draw_not_finished(),
+            };
+        }
+
+        self.dirty = true;
+
+        let mut score_str = String::new();
+        format_score(score, &mut score_str);
+
+        let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+        Text::with_alignment("Score", Point::new(32, 60), style, Alignment::Center)
+            .draw(&mut self.handle)?;
+
+        if score_str.len() > LINE_CHARS {
+            // Cut at the right edge, `scroll_long_score` shows the rest of it
+            Text::new(&score_str, Point::new(0, GAME_OVER_SCORE_Y), style)
+                .draw(&mut self.handle)?;
+        } else {
+            Text::with_alignment(
+                &score_str,
+                Point::new(32, GAME_OVER_SCORE_Y),
+                style,
+                Alignment::Center,
+            )
+            .draw(&mut self.handle)?;
+        }
+
+        Ok(())
+    }
+
+    /// Scrolls the score of the game over screen across the display once when it is too long
+    /// to fit on it, the start of the score is left in place afterwards
+    pub fn scroll_long_score(
+        &mut self,
+        score: u64,
+        mode: GameMode,
+        timer: &mut impl DelayNs,
+    ) -> Result<(), DisplayError> {
+        let mut score_str: String<SCORE_TEXT_LEN> = String::new();
+        format_score(score, &mut score_str);
+
+        if matches!(mode, GameMode::Sprint { .. }) || score_str.len() <= LINE_CHARS {
+            return Ok(());
+        }
+
+        let line = Text::new(
+            &score_str,
+            Point::new(0, GAME_OVER_SCORE_Y),
+            MonoTextStyle::new(&FONT_6X10, BinaryColor::On),
+        );
+        line.bounding_box()
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(BinaryColor::Off)
+                    .build(),
+            )
+            .draw(&mut self.handle)?;
+
+        self.scroll_text(
+            &score_str,
+            GAME_OVER_SCORE_Y,
+            SCORE_SCROLL_STEP,
+            SCORE_SCROLL_DELAY_NS,
+            timer,
+        )?;
+
+        line.draw(&mut self.handle)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Game over screen together with the bottom rows of the final board at a small scale,
+    /// to show how the game ended
+    pub fn draw_game_over_with_board<const C: usize, const R: usize>(
+        &mut self,
+        score: u64,
+        mode: GameMode,
+        time_ms: Option<u64>,
+        board: &Board<C, R>,
+    ) -> Result<(), DisplayError> {
+        self.draw_game_over(score, mode, time_ms)?;
+
+        let offset_x = (DISPLAY_WIDTH - C as i16 * GAME_OVER_CELL_SIZE) / 2;
+        let rows = GAME_OVER_ROWS.min(R);
+
+        Rectangle::new(
+            Point::new(offset_x as i32, GAME_OVER_BOARD_Y as i32),
+            Size::new(
+                (C as i16 * GAME_OVER_CELL_SIZE) as u32,
+                (rows as i16 * GAME_OVER_CELL_SIZE) as u32,
+            ),
+        )
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_color(BinaryColor::On)
+                .stroke_width(1)
+                .stroke_alignment(StrokeAlignment::Outside)
+                .build(),
+        )
+        .draw(&mut self.handle)?;
+
+        let cell_size = Size::new(GAME_OVER_CELL_SIZE as u32, GAME_OVER_CELL_SIZE as u32);
+        let fill = PrimitiveStyleBuilder::new()
+            .fill_color(BinaryColor::On)
+            .build();
+
+        for (dy, row) in board.iter_rows_from(R - rows).enumerate() {
+            for (dx, cell) in row.iter().enumerate() {
+                if !cell.is_occupied() {
+                    continue;
+                }
+
+                let origin = Point::new(
+                    (offset_x + dx as i16 * GAME_OVER_CELL_SIZE) as i32,
+                    (GAME_OVER_BOARD_Y + dy as i16 * GAME_OVER_CELL_SIZE) as i32,
+                );
+                Rectangle::new(origin, cell_size)
+                    .into_styled(fill)
+                    .draw(&mut self.handle)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<I2C: I2c> Display<I2C, 0> {
+    /// Display with the size of a cell chosen at runtime, e.g. for a board size read
+    /// from a configuration, instead of by the `SIZE_MUL` of the build
+    pub fn new_dynamic(i2c: I2C, size_mul: i16) -> Self {
+        assert!(
+            matches!(size_mul, 1 | 5..=10),
+            "size_mul must be 1 for a pixel per cell, or between 5 to fit the cell patterns and 10 to fit the board"
         );
 
-        score.draw(&mut self.handle).unwrap();
-        self.flush();
+        Self::with_size_mul(i2c, size_mul)
+    }
+}
+
+/// Drawing with `embedded_graphics` directly, the display is in the portrait orientation
+impl<I2C: I2c, const SIZE_MUL: i16> DrawTarget for Display<I2C, SIZE_MUL> {
+    type Color = BinaryColor;
+    type Error = DisplayError;
+
+    fn draw_iter<P>(&mut self, pixels: P) -> Result<(), Self::Error>
+    where
+        P: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        self.dirty = true;
+        self.handle.draw_iter(pixels)
     }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        self.dirty = true;
+        DrawTarget::fill_solid(&mut self.handle, area, color)
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.dirty = true;
+        DrawTarget::clear(&mut self.handle, color)
+    }
+}
+
+impl<I2C: I2c, const SIZE_MUL: i16> OriginDimensions for Display<I2C, SIZE_MUL> {
+    fn size(&self) -> Size {
+        OriginDimensions::size(&self.handle)
+    }
+}
+
+/// Writes the score into the buffer with a comma every three digits, e.g. `12,345,678`.
+/// The buffer fits `u64::MAX`, 20 digits and 6 commas
+fn format_score(score: u64, buf: &mut String<SCORE_TEXT_LEN>) {
+    let mut digits: String<SCORE_DIGITS> = String::new();
+    write!(&mut digits, "{}", score).unwrap();
+
+    buf.clear();
+
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            buf.push(',').unwrap();
+        }
+
+        buf.push(digit).unwrap();
+    }
+}
+
+/// Offset in pixels which centers the piece inside `NEXT_PIECE_AREA` cells of the given size
+fn center_offset_in_panel(piece: Tetromino, rotation: Rotation, cell_size: i16) -> Coordination {
+    let blocks = tetris::get_tetromino_blocks(piece, rotation);
+
+    let min_x = blocks.iter().map(|b| b.x).min().unwrap_or(0);
+    let max_x = blocks.iter().map(|b| b.x).max().unwrap_or(0);
+    let min_y = blocks.iter().map(|b| b.y).min().unwrap_or(0);
+    let max_y = blocks.iter().map(|b| b.y).max().unwrap_or(0);
+
+    let width = max_x - min_x + 1;
+    let height = max_y - min_y + 1;
+
+    Coordination {
+        x: (NEXT_PIECE_AREA - width) * cell_size / 2 - min_x * cell_size,
+        y: (NEXT_PIECE_AREA - height) * cell_size / 2 - min_y * cell_size,
+    }
+}
+
+/// Rotation of the piece with the smallest height, so it does not overlap the board
+fn preview_rotation(piece: Tetromino) -> Rotation {
+    let height = |rotation| {
+        let blocks = tetris::get_tetromino_blocks(piece, rotation);
+        let min_y = blocks.iter().map(|b| b.y).min().unwrap_or(0);
+        let max_y = blocks.iter().map(|b| b.y).max().unwrap_or(0);
+        max_y - min_y
+    };
+
+    [
+        Rotation::Default,
+        Rotation::Left,
+        Rotation::Flipped,
+        Rotation::Right,
+    ]
+    .into_iter()
+    .min_by_key(|&rotation| height(rotation))
+    .unwrap_or_default()
+}
+
+fn random_column(rng: &mut impl RngCore) -> u8 {
+    (rng.next_u32() % DISPLAY_WIDTH as u32) as u8
 }
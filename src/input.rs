@@ -1,20 +1,49 @@
 use crate::hal;
 use core::cmp::Ordering;
+use core::marker::PhantomData;
+use embedded_hal::digital::InputPin as _;
 use hal::gpio;
+use heapless::Vec;
 
 const DELAY_BETWEEN_INTERRUPTS: u64 = 130;
 const JOYSTICK_DEADZONE: u32 = 1000;
+/// Distance from the center needed to leave the center, 1.25 times the deadzone
+const JOYSTICK_ENTER_THRESHOLD: u32 = JOYSTICK_DEADZONE * 5 / 4;
+/// Distance from the center needed to stay in a direction, 0.75 times the deadzone
+const JOYSTICK_EXIT_THRESHOLD: u32 = JOYSTICK_DEADZONE * 3 / 4;
+/// Maximum deflection of the joystick from its center, the ADC is 12-bit
+const JOYSTICK_MAX_DEFLECTION: u32 = 2048;
+/// Auto repeat rate of the soft drop when the joystick is pushed half way
+const SOFT_DROP_HALF_ARR_MS: u64 = 33;
+/// Delay before a held sideways movement starts repeating
+pub const DAS_DELAY_MS: u64 = 167;
+/// Auto repeat rate of a held sideways movement
+pub const ARR_MS: u64 = 33;
+/// Maximum number of repeat events reported at once
+const DAS_MAX_EVENTS: usize = 8;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Input {
     JoystickButton,
+    /// The joystick button has been held for a while
+    JoystickButtonLongPress,
     Joystick(JoystickState),
 }
 
+/// How long a button was held down
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PressKind {
+    /// Released before it counted as a long press
+    Tap,
+    LongPress,
+}
+
 pub struct Joystick {
     center_x: u16,
     center_y: u16,
     last_state: JoystickState,
+    /// The joystick is pushed in a direction, selects the threshold of the hysteresis
+    in_zone: bool,
 }
 
 #[derive(Default, PartialEq, Clone, Copy)]
@@ -34,11 +63,13 @@ impl Joystick {
             center_x,
             center_y,
             last_state: JoystickState::Center,
+            in_zone: false,
         }
     }
 
     pub fn state_from(&mut self, x: u16, y: u16) -> Option<JoystickState> {
-        let state = self.calculate_state(x, y);
+        let state = self.compute_state(x, y);
+        self.in_zone = state != JoystickState::Center;
 
         if state != self.last_state {
             self.last_state = state;
@@ -48,7 +79,37 @@ impl Joystick {
         }
     }
 
-    fn calculate_state(&self, x: u16, y: u16) -> JoystickState {
+    pub fn center_x(&self) -> u16 {
+        self.center_x
+    }
+
+    pub fn center_y(&self) -> u16 {
+        self.center_y
+    }
+
+    /// State of the joystick from the last reading
+    pub fn state(&self) -> JoystickState {
+        self.last_state
+    }
+
+    /// Deflection of the joystick from its center, scaled to 0..=255
+    pub fn magnitude(&self, x: u16, y: u16) -> u8 {
+        let dx = u32::from(x.abs_diff(self.center_x));
+        let dy = u32::from(y.abs_diff(self.center_y));
+        let distance = isqrt(dx.pow(2) + dy.pow(2)).min(JOYSTICK_MAX_DEFLECTION);
+
+        (distance * 255 / JOYSTICK_MAX_DEFLECTION) as u8
+    }
+
+    /// Auto repeat rate of the soft drop in milliseconds for the given magnitude.
+    /// It is instantaneous at full deflection and 33ms at half of it
+    pub fn soft_drop_arr_ms(magnitude: u8) -> u64 {
+        (255 - magnitude as u64) * SOFT_DROP_HALF_ARR_MS * 2 / 255
+    }
+
+    /// State of the joystick for the given reading without updating the last state,
+    /// so the input can be previewed without consuming it
+    pub fn compute_state(&self, x: u16, y: u16) -> JoystickState {
         let is_x_positive = x > self.center_x;
         let is_y_positive = y > self.center_y;
 
@@ -74,35 +135,260 @@ impl Joystick {
         }
     }
 
-    // Calculate the euclidean distance between the center and the current position
+    // Calculate the euclidean distance between the center and the current position.
+    // The deadzone is larger when entering a direction than when staying in it,
+    // so the noise of the ADC around the edge does not toggle the direction
     fn is_in_deadzone(&self, dx: u16, dy: u16) -> bool {
-        u32::from(dx).pow(2) + u32::from(dy).pow(2) <= JOYSTICK_DEADZONE.pow(2)
+        let threshold = if self.in_zone {
+            JOYSTICK_EXIT_THRESHOLD
+        } else {
+            JOYSTICK_ENTER_THRESHOLD
+        };
+
+        u32::from(dx).pow(2) + u32::from(dy).pow(2) <= threshold.pow(2)
+    }
+}
+
+/// Inputs waiting for the main loop, so the interrupt does not have to handle them
+pub struct InputEventBuffer<const N: usize>(Vec<Input, N>);
+
+impl<const N: usize> InputEventBuffer<N> {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Queues the input, false when the buffer is full and the input is dropped
+    pub fn push(&mut self, input: Input) -> bool {
+        self.0.push(input).is_ok()
+    }
+
+    /// Empties the buffer, the inputs are returned in the order they were pushed
+    pub fn drain(&mut self) -> impl Iterator<Item = Input> {
+        core::mem::take(&mut self.0).into_iter()
+    }
+}
+
+/// Timing of the delayed auto shift, `DAS_DELAY_MS` and `ARR_MS` by default
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DasConfig {
+    /// Delay before a held direction starts repeating
+    pub das_ms: u64,
+    /// Interval between the repeats, at least 1ms
+    pub arr_ms: u64,
+}
+
+impl DasConfig {
+    pub const fn new(das_ms: u64, arr_ms: u64) -> Self {
+        Self {
+            das_ms,
+            arr_ms: if arr_ms == 0 { 1 } else { arr_ms },
+        }
+    }
+}
+
+impl Default for DasConfig {
+    fn default() -> Self {
+        Self::new(DAS_DELAY_MS, ARR_MS)
+    }
+}
+
+/// Delayed auto shift of the sideways movement while the joystick is held.
+/// The repeats are counted from the moment the direction was pressed, so none of them
+/// are lost when a frame takes longer than usual
+pub struct DasState {
+    held: JoystickState,
+    pressed_at_ms: u64,
+    /// Number of repeat events reported since the direction was pressed
+    emitted: u64,
+    config: DasConfig,
+}
+
+impl DasState {
+    pub fn new(config: DasConfig) -> Self {
+        Self {
+            held: JoystickState::Center,
+            pressed_at_ms: 0,
+            emitted: 0,
+            config,
+        }
+    }
+
+    /// Starts tracking the new state of the joystick, only left and right are repeated
+    pub fn press(&mut self, state: JoystickState, current_ms: u64) {
+        self.held = match state {
+            JoystickState::Left | JoystickState::Right => state,
+            _ => JoystickState::Center,
+        };
+        self.pressed_at_ms = current_ms;
+        self.emitted = 0;
+    }
+
+    /// Returns the repeat events which should have fired since the last call.
+    /// The first repeat fires `das_ms` of the config after the press, then every `arr_ms`
+    pub fn events_since_last_frame(&mut self, current_ms: u64) -> Vec<Input, DAS_MAX_EVENTS> {
+        let mut events = Vec::new();
+
+        if self.held == JoystickState::Center {
+            return events;
+        }
+
+        let held_ms = current_ms.saturating_sub(self.pressed_at_ms);
+        if held_ms < self.config.das_ms {
+            return events;
+        }
+
+        let due = 1 + (held_ms - self.config.das_ms) / self.config.arr_ms;
+        let pending = due.saturating_sub(self.emitted);
+
+        // Anything over the capacity is dropped instead of lagging behind the player
+        for _ in 0..pending.min(DAS_MAX_EVENTS as u64) {
+            let _ = events.push(Input::Joystick(self.held));
+        }
+
+        self.emitted = due;
+        events
+    }
+}
+
+/// Median of the samples, which are sorted in place by insertion sort
+pub fn median(samples: &mut [u16]) -> u16 {
+    for i in 1..samples.len() {
+        let mut j = i;
+
+        while j > 0 && samples[j - 1] > samples[j] {
+            samples.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    samples[samples.len() / 2]
+}
+
+/// Integer square root using the Newton's method
+fn isqrt(n: u32) -> u32 {
+    if n < 2 {
+        return n;
+    }
+
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
     }
+
+    x
+}
+
+/// Electrical polarity of a button, given by the pull resistor of its pin
+pub trait Polarity {
+    type Pull: gpio::PullType;
+    /// Edge of the signal when the button is pressed
+    const PRESS_EDGE: gpio::Interrupt;
+    /// Level of the pin while the button is pressed
+    const ACTIVE_HIGH: bool;
 }
 
-pub struct Button<PIN: gpio::PinId> {
+/// The button connects the pin to the ground, the pin is pulled up
+pub struct ActiveLow;
+
+/// The button connects the pin to the supply, the pin is pulled down
+pub struct ActiveHigh;
+
+impl Polarity for ActiveLow {
+    type Pull = gpio::PullUp;
+    const PRESS_EDGE: gpio::Interrupt = gpio::Interrupt::EdgeLow;
+    const ACTIVE_HIGH: bool = false;
+}
+
+impl Polarity for ActiveHigh {
+    type Pull = gpio::PullDown;
+    const PRESS_EDGE: gpio::Interrupt = gpio::Interrupt::EdgeHigh;
+    const ACTIVE_HIGH: bool = true;
+}
+
+pub struct Button<PIN: gpio::PinId, P: Polarity = ActiveLow> {
     last_interrupt: hal::timer::Instant,
-    pin: gpio::Pin<PIN, gpio::FunctionSioInput, gpio::PullUp>,
+    /// Start of the press which has not been classified by `press_kind` yet
+    pressed_since: Option<hal::timer::Instant>,
+    pin: gpio::Pin<PIN, gpio::FunctionSioInput, P::Pull>,
+    _polarity: PhantomData<P>,
 }
 
-impl<PIN: gpio::PinId> Button<PIN> {
+impl<PIN: gpio::PinId> Button<PIN, ActiveLow> {
     pub fn new(pin: gpio::Pin<PIN, gpio::FunctionSioInput, gpio::PullUp>) -> Self {
-        pin.set_interrupt_enabled(gpio::Interrupt::EdgeLow, true);
+        Self::with_pin(pin)
+    }
+}
+
+impl<PIN: gpio::PinId, P: Polarity> Button<PIN, P> {
+    fn with_pin(pin: gpio::Pin<PIN, gpio::FunctionSioInput, P::Pull>) -> Self {
+        pin.set_interrupt_enabled(P::PRESS_EDGE, true);
 
         Self {
             last_interrupt: hal::timer::Instant::from_ticks(0),
+            pressed_since: None,
             pin,
+            _polarity: PhantomData,
+        }
+    }
+
+    /// The button is being held down
+    pub fn is_pressed(&mut self) -> bool {
+        let level = if P::ACTIVE_HIGH {
+            self.pin.is_high()
+        } else {
+            self.pin.is_low()
+        };
+
+        level.unwrap_or(false)
+    }
+
+    /// Kind of the current press, reported once for each press. A long press is reported
+    /// as soon as the button has been held for `long_press_ms`, a tap when it is released
+    /// before that. Has to be polled to notice the release and the hold
+    pub fn press_kind(
+        &mut self,
+        current_time: hal::timer::Instant,
+        long_press_ms: u64,
+    ) -> Option<PressKind> {
+        if self.interrupted(current_time) {
+            self.pressed_since = Some(current_time);
+        }
+
+        let start = self.pressed_since?;
+        let held_ms = current_time
+            .checked_duration_since(start)
+            .map_or(0, |duration| duration.to_millis());
+
+        if held_ms >= long_press_ms {
+            self.pressed_since = None;
+            Some(PressKind::LongPress)
+        } else if !self.is_pressed() {
+            self.pressed_since = None;
+            Some(PressKind::Tap)
+        } else {
+            None
         }
     }
 
     pub fn interrupted(&mut self, current_time: hal::timer::Instant) -> bool {
-        let result = self.pin.interrupt_status(gpio::Interrupt::EdgeLow);
+        self.interrupted_inner(current_time, P::PRESS_EDGE)
+    }
+
+    fn interrupted_inner(
+        &mut self,
+        current_time: hal::timer::Instant,
+        edge: gpio::Interrupt,
+    ) -> bool {
+        let result = self.pin.interrupt_status(edge);
 
         if !result {
             return false;
         }
 
-        self.pin.clear_interrupt(gpio::Interrupt::EdgeLow);
+        self.pin.clear_interrupt(edge);
 
         // Debouncing
         if let Some(duration) = current_time.checked_duration_since(self.last_interrupt) {
@@ -115,3 +401,51 @@ impl<PIN: gpio::PinId> Button<PIN> {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_direction_repeats_after_the_delay() {
+        let mut das = DasState::new(DasConfig::default());
+        das.press(JoystickState::Left, 1000);
+
+        assert!(das
+            .events_since_last_frame(1000 + DAS_DELAY_MS - 1)
+            .is_empty());
+        assert_eq!(das.events_since_last_frame(1000 + DAS_DELAY_MS).len(), 1);
+        assert!(das
+            .events_since_last_frame(1000 + DAS_DELAY_MS + ARR_MS - 1)
+            .is_empty());
+        assert_eq!(
+            das.events_since_last_frame(1000 + DAS_DELAY_MS + 2 * ARR_MS)
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn repeats_of_a_long_frame_are_not_lost() {
+        let mut das = DasState::new(DasConfig::new(100, 10));
+        das.press(JoystickState::Right, 0);
+
+        // The first repeat at 100ms and five more every 10ms
+        let events = das.events_since_last_frame(150);
+        assert_eq!(events.len(), 6);
+        assert!(events
+            .iter()
+            .all(|&event| event == Input::Joystick(JoystickState::Right)));
+    }
+
+    #[test]
+    fn only_sideways_movement_is_repeated() {
+        let mut das = DasState::new(DasConfig::new(100, 0));
+        das.press(JoystickState::Down, 0);
+        assert!(das.events_since_last_frame(1000).is_empty());
+
+        das.press(JoystickState::Left, 1000);
+        das.press(JoystickState::Center, 1050);
+        assert!(das.events_since_last_frame(2000).is_empty());
+    }
+}
@@ -1,22 +1,27 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
 mod bgm;
 mod display;
 mod input;
+mod render;
 mod tetris;
+#[cfg(feature = "usb-debug")]
+mod usb_debug;
 
 // Ensure we halt the program on panic (if we don't mention this crate it won't
 // be linked)
+#[cfg(not(test))]
 use panic_halt as _;
 
 use core::cell::RefCell;
-use core::mem;
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
 use cortex_m::prelude::_embedded_hal_adc_OneShot;
 use critical_section::Mutex;
 use display::Display;
-use embedded_hal::delay::DelayNs as _;
-use embedded_hal::digital::StatefulOutputPin;
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{OutputPin, StatefulOutputPin};
+use embedded_hal::i2c::I2c;
 use embedded_hal::pwm::SetDutyCycle as _;
 use rp235x_hal as hal;
 
@@ -27,8 +32,11 @@ use hal::pac::interrupt;
 use hal::pwm::{Slice, SliceId, ValidSliceMode};
 use hal::rosc::{self, RingOscillator};
 
-use input::{Button, Input, Joystick, JoystickState};
-use tetris::{BoardUpdate, Cell, Rotation, State as GameState, Tetris, Tetromino};
+use input::{
+    Button, DasConfig, DasState, Input, InputEventBuffer, Joystick, JoystickState, PressKind,
+};
+use render::Renderer;
+use tetris::{BoardUpdate, GameMode, Tetris};
 
 /// Tell the Boot ROM about our application
 #[link_section = ".start_block"]
@@ -38,25 +46,181 @@ pub static IMAGE_DEF: hal::block::ImageDef = hal::block::ImageDef::secure_exe();
 /// External high-speed crystal on the Raspberry Pi Pico 2 board is 12 MHz.
 const XTAL_FREQ_HZ: u32 = 12_000_000u32;
 
+/// System clock configured by `init_clocks_and_plls`, the note frequencies are calculated for it
+const SYSTEM_CLOCK_HZ: u32 = 150_000_000u32;
+
 /// Refresh rate of the game in nanoseconds
 /// one ADC sampling takes 92ns for each input, so we subtract 2 of them (for the joystick)
 /// from the refresh rate
 const REFRESH_RATE_NS: u32 = 1_000_000_000 / 60 - 4000;
 const TETRIS_WIDTH: usize = 10;
 const TETRIS_HEIGHT: usize = 20;
+/// Size of a cell on the display in pixels
+const BOARD_SIZE_MUL: i16 = display::board_size_mul(TETRIS_WIDTH, TETRIS_HEIGHT);
+/// Size of the board on the display in pixels, without its border
+const BOARD_PIXEL_WIDTH: i16 = TETRIS_WIDTH as i16 * BOARD_SIZE_MUL;
+const BOARD_PIXEL_HEIGHT: i16 = TETRIS_HEIGHT as i16 * BOARD_SIZE_MUL;
+
+const _: () = assert!(
+    display::board_fits(BOARD_PIXEL_WIDTH, BOARD_PIXEL_HEIGHT),
+    "the board does not fit on the display"
+);
 
 /// Volume of the buzzer, or duty cycle of the PWM
 const VOLUME: u8 = 1;
-const COMMAND_PLAY: u32 = 0x1;
-const COMMAND_STOP: u32 = 0x0;
+/// Volume of the sound effects, played on their own PWM slice mixed with the melody
+/// by resistors in front of the buzzer
+const EFFECT_VOLUME: u8 = 3;
+/// Number of steps of fading the music in and out
+const VOLUME_RAMP_STEPS: u8 = 10;
+const VOLUME_RAMP_STEP_MS: u32 = 5;
+
+/// Messages sent between the cores through the SIO FIFO
+#[derive(Clone, Copy, PartialEq)]
+enum SioCommand {
+    /// Core 0 -> Core 1: stop the music
+    Stop,
+    /// Core 0 -> Core 1: start the music
+    Play,
+    /// Core 0 -> Core 1: play the sound effect over the music
+    PlayEffect(bgm::Effect),
+    /// Core 0 -> Core 1: level of the game, the music gets faster with it
+    SetLevel(u8),
+    /// Core 1 -> Core 0: progress of the current music loop in percent
+    Progress(u8),
+    /// Core 1 -> Core 0: the command with the given sequence number has been processed
+    Ack(u8),
+}
+
+impl SioCommand {
+    const STOP: u32 = 0x0;
+    const PLAY: u32 = 0x1;
+    const PROGRESS: u32 = 0x100;
+    const PLAY_EFFECT: u32 = 0x200;
+    const SET_LEVEL: u32 = 0x400;
+    const ACK: u32 = 0x500;
+
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            Self::STOP => Some(Self::Stop),
+            Self::PLAY => Some(Self::Play),
+            v if v & !0xFF == Self::PROGRESS => Some(Self::Progress(v as u8)),
+            v if v & !0xFF == Self::PLAY_EFFECT => {
+                bgm::Effect::from_u8(v as u8).map(Self::PlayEffect)
+            }
+            v if v & !0xFF == Self::SET_LEVEL => Some(Self::SetLevel(v as u8)),
+            v if v & !0xFF == Self::ACK => Some(Self::Ack(v as u8)),
+            _ => None,
+        }
+    }
+}
+
+impl From<SioCommand> for u32 {
+    fn from(command: SioCommand) -> u32 {
+        match command {
+            SioCommand::Stop => SioCommand::STOP,
+            SioCommand::Play => SioCommand::PLAY,
+            SioCommand::PlayEffect(effect) => SioCommand::PLAY_EFFECT | effect as u32,
+            SioCommand::SetLevel(level) => SioCommand::SET_LEVEL | level as u32,
+            SioCommand::Progress(percent) => SioCommand::PROGRESS | percent as u32,
+            SioCommand::Ack(sequence) => SioCommand::ACK | sequence as u32,
+        }
+    }
+}
+
+/// Core 1 acknowledges every command sent to it
+const RELIABLE_FIFO: bool = true;
+/// Core 1 checks for commands after every note, the longest note of the melody is shorter than this.
+/// A command not acknowledged by then is taken as lost
+const FIFO_ACK_TIMEOUT_MS: u64 = 1000;
+/// The sequence number of a command is sent in the bits above its code
+const SEQUENCE_SHIFT: u32 = 16;
+const COMMAND_MASK: u32 = (1 << SEQUENCE_SHIFT) - 1;
+/// Number of commands waiting for their acknowledgement which are kept track of
+const MAX_PENDING_COMMANDS: usize = 8;
+
+/// Size of the core 1 stack in words
+const CORE1_STACK_WORDS: usize = 4096;
 
 /// Declare a memory to be used by core 1
-static mut CORE1_STACK: Stack<4096> = Stack::new();
+static mut CORE1_STACK: Stack<CORE1_STACK_WORDS> = Stack::new();
+
+/// Reason why core 1 could not be spawned, 0 when it was spawned successfully
+/// 1 for `multicore::Error::InvalidCore` and 2 for `multicore::Error::Unresponsive`
+static CORE1_SPAWN_ERROR: AtomicU8 = AtomicU8::new(0);
+
+/// Level of the game the music is played at, only used by core 1
+static MUSIC_LEVEL: AtomicU8 = AtomicU8::new(1);
+
+/// Number of lines to clear in the sprint mode
+const SPRINT_LINES: u32 = 40;
+
+/// How long the joystick button has to be held to hold the piece instead of dropping it
+const LONG_PRESS_MS: u64 = 400;
+
+/// Number of readings of the joystick at startup, the median of them is its center
+const JOYSTICK_CALIBRATION_SAMPLES: usize = 16;
+const JOYSTICK_CALIBRATION_GAP_MS: u32 = 10;
+
+/// Number of failed joystick readings
+static ADC_ERROR_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Delay between the actions of the auto play, so the moves can be followed on the display
+#[cfg(feature = "ai")]
+const AI_ACTION_DELAY_MS: u64 = 100;
+
+/// Options chosen on the start screen
+#[cfg(feature = "ai")]
+#[derive(Default, Clone, Copy, PartialEq)]
+enum GameOption {
+    #[default]
+    Manual,
+    /// The game plays itself using `Tetris::ai_suggest_action`
+    AutoPlay,
+}
 
 struct State {
     game: Tetris<TETRIS_WIDTH, TETRIS_HEIGHT, RingOscillator<rosc::Enabled>>,
     board_updated: BoardUpdate<16>,
-    last_move_down: hal::timer::Instant,
+    /// Mode of the next game, switched by pushing the joystick down while no game is running
+    mode: GameMode,
+    /// The shadow game placed a piece since its board was last sent to the host
+    #[cfg(feature = "shadow-mode")]
+    shadow_placed: bool,
+    #[cfg(feature = "ai")]
+    option: GameOption,
+}
+
+impl State {
+    /// Applies the action to the game, and to its shadow game in the shadow mode
+    fn act(&mut self, action: tetris::Action) {
+        #[cfg(not(feature = "shadow-mode"))]
+        let board_update = self.game.act(action);
+
+        #[cfg(feature = "shadow-mode")]
+        let board_update = {
+            let (board_update, shadow_update) = self.game.run_shadow(action);
+            self.shadow_placed |= shadow_update.is_full();
+            board_update
+        };
+
+        self.board_updated.merge(board_update);
+    }
+
+    /// Lets the piece fall, in the shadow game too in the shadow mode
+    fn gravity_tick(&mut self, now_ms: u64) {
+        #[cfg(not(feature = "shadow-mode"))]
+        let board_update = self.game.gravity_tick(now_ms);
+
+        #[cfg(feature = "shadow-mode")]
+        let board_update = {
+            let (board_update, shadow_update) = self.game.run_shadow_gravity(now_ms);
+            self.shadow_placed |= shadow_update.is_full();
+            board_update
+        };
+
+        self.board_updated.merge(board_update);
+    }
 }
 
 struct Buttons {
@@ -72,13 +236,20 @@ struct InputHandleTools {
 static GLOBAL_STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State {
     game: Tetris::new(),
     board_updated: BoardUpdate::Full,
-    last_move_down: hal::timer::Instant::from_ticks(0),
+    mode: GameMode::Marathon,
+    #[cfg(feature = "shadow-mode")]
+    shadow_placed: false,
+    #[cfg(feature = "ai")]
+    option: GameOption::Manual,
 }));
 
 static GLOBAL_BUTTONS: Mutex<RefCell<Option<Buttons>>> = Mutex::new(RefCell::new(None));
 // static GLOBAL_JOYSTICK: Mutex<RefCell<Option<Joystick>>> = Mutex::new(RefCell::new(None));
 static GLOBAL_INPUT_HANDLE_TOOLS: Mutex<RefCell<Option<InputHandleTools>>> =
     Mutex::new(RefCell::new(None));
+/// Inputs from the interrupt, handled at the start of the next frame
+static GLOBAL_INPUT_BUFFER: Mutex<RefCell<InputEventBuffer<8>>> =
+    Mutex::new(RefCell::new(InputEventBuffer::new()));
 
 /// Entry point to our bare-metal application.
 ///
@@ -87,7 +258,7 @@ static GLOBAL_INPUT_HANDLE_TOOLS: Mutex<RefCell<Option<InputHandleTools>>> =
 ///
 /// The function configures the rp235x peripherals, then toggles a GPIO pin in
 /// an infinite loop. If there is an LED connected to that pin, it will blink.
-#[hal::entry]
+#[cfg_attr(not(test), hal::entry)]
 fn main() -> ! {
     // Grab our singleton objects
     let mut pac = hal::pac::Peripherals::take().unwrap();
@@ -112,18 +283,6 @@ fn main() -> ! {
 
     let mut timer = hal::Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
 
-    // Spawn core 1 for background music handle
-    let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
-    let cores = mc.cores();
-    let core1 = &mut cores[1];
-    let timer_1 = timer.clone();
-
-    core1
-        .spawn(unsafe { &mut CORE1_STACK.mem }, move || {
-            core1_task(timer_1);
-        })
-        .unwrap();
-
     // Set the pins to their default state
     let pins = gpio::Pins::new(
         pac.IO_BANK0,
@@ -144,12 +303,49 @@ fn main() -> ! {
         &clocks.system_clock,
     );
 
-    let mut display: Display<_, 5> = Display::init(i2c);
+    let renderer: Renderer<_, BOARD_SIZE_MUL> = Renderer::new(Display::init(i2c));
+
+    #[cfg(feature = "usb-debug")]
+    let usb_bus = usb_device::bus::UsbBusAllocator::new(hal::usb::UsbBus::new(
+        pac.USB,
+        pac.USB_DPRAM,
+        clocks.usb_clock,
+        true,
+        &mut pac.RESETS,
+    ));
+
+    #[cfg(feature = "usb-debug")]
+    let usb_debug = usb_debug::UsbDebug::new(&usb_bus);
+
     let rnd = RingOscillator::new(pac.ROSC).initialize();
     let mut adc = hal::adc::Adc::new(pac.ADC, &mut pac.RESETS);
 
     // Onboard LED
-    let led = pins.gpio25.reconfigure();
+    let mut led = pins.gpio25.reconfigure();
+
+    // Spawn core 1 for background music handle
+    let mut mc = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
+    let cores = mc.cores();
+    let core1 = &mut cores[1];
+    let timer_1 = timer.clone();
+
+    let spawn_result = core1.spawn(unsafe { &mut CORE1_STACK.mem }, move || {
+        core1_task(timer_1);
+    });
+
+    if let Err(error) = spawn_result {
+        let error_code = match error {
+            hal::multicore::Error::InvalidCore => 1,
+            hal::multicore::Error::Unresponsive => 2,
+        };
+
+        CORE1_SPAWN_ERROR.store(error_code, Ordering::Relaxed);
+
+        loop {
+            blink_sos(&mut led, &mut timer);
+        }
+    }
+
     let buttons = Buttons {
         joystick_btn: input::Button::new(pins.gpio22.reconfigure()),
         timer: timer.clone(),
@@ -157,14 +353,20 @@ fn main() -> ! {
     let mut joystick_x = hal::adc::AdcPin::new(pins.gpio27.into_floating_input()).unwrap();
     let mut joystick_y = hal::adc::AdcPin::new(pins.gpio26.into_floating_input()).unwrap();
 
-    let mut joystick_handle = Joystick::new(
-        adc.read(&mut joystick_y).unwrap(),
-        adc.read(&mut joystick_x).unwrap(),
-    );
+    let (center_x, center_y) =
+        read_joystick_center(&mut adc, &mut joystick_x, &mut joystick_y, &mut timer);
+    let joystick_handle = Joystick::new(center_y, center_x);
 
     // Initialize the global states
     critical_section::with(|cs| {
-        GLOBAL_STATE.borrow(cs).borrow_mut().game.set_rng(rnd);
+        let mut state = GLOBAL_STATE.borrow(cs).borrow_mut();
+        state.game.set_rng(rnd);
+        #[cfg(feature = "shadow-mode")]
+        state
+            .game
+            .enable_shadow(tetris::RefillStrategy::TrulyRandom);
+        drop(state);
+
         GLOBAL_BUTTONS.borrow(cs).replace(Some(buttons));
         // GLOBAL_JOYSTICK.borrow(cs).replace(Some(joystick));
         GLOBAL_INPUT_HANDLE_TOOLS
@@ -181,78 +383,132 @@ fn main() -> ! {
         cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::IO_IRQ_BANK0);
     }
 
+    core0_task(
+        renderer,
+        JoystickInput {
+            adc,
+            pin_x: joystick_x,
+            pin_y: joystick_y,
+            handle: joystick_handle,
+        },
+        sio.fifo,
+        timer,
+        #[cfg(feature = "usb-debug")]
+        usb_debug,
+    )
+}
+
+/// Joystick of the game with the ADC reading it
+struct JoystickInput<X, Y> {
+    adc: hal::adc::Adc,
+    pin_x: X,
+    pin_y: Y,
+    handle: Joystick,
+}
+
+/// Core 0 task running the game. `main` sets up the peripherals and spawns `core1_task`,
+/// then hands over to this loop, which reads the inputs, advances the game and draws it
+/// every frame while core 1 plays the music
+fn core0_task<I2C: I2c, X, Y>(
+    mut renderer: Renderer<I2C, BOARD_SIZE_MUL>,
+    mut joystick: JoystickInput<X, Y>,
+    mut fifo: hal::sio::SioFifo,
+    mut timer: hal::Timer<hal::timer::CopyableTimer0>,
+    #[cfg(feature = "usb-debug")] mut usb_debug: usb_debug::UsbDebug<'_>,
+) -> !
+where
+    hal::adc::Adc: _embedded_hal_adc_OneShot<hal::adc::Adc, u16, X>
+        + _embedded_hal_adc_OneShot<hal::adc::Adc, u16, Y>,
+{
+    let mut last_soft_drop = timer.get_counter();
+    let mut das = DasState::new(DasConfig::default());
+    let mut link = SioLink::new(fifo);
+    #[cfg(feature = "ai")]
+    let mut last_ai_action = timer.get_counter();
+
     loop {
-        // Poll joystick first
-        let joystick_x = adc.read(&mut joystick_x).unwrap();
-        let joystick_y = adc.read(&mut joystick_y).unwrap();
+        #[cfg(feature = "usb-debug")]
+        usb_debug.poll();
 
-        if let Some(state) = joystick_handle.state_from(joystick_y, joystick_x) {
-            input_handler(Input::Joystick(state));
-        }
+        poll_button();
 
-        critical_section::with(|cs| {
-            let mut state = GLOBAL_STATE.borrow(cs).borrow_mut();
+        let inputs =
+            critical_section::with(|cs| GLOBAL_INPUT_BUFFER.borrow(cs).borrow_mut().drain());
+        for input in inputs {
+            input_handler(input);
+        }
 
-            if state.game.is_playing() {
-                let instant = timer.get_counter();
-                if let Some(duration) = instant.checked_duration_since(state.last_move_down) {
-                    if duration.to_millis() >= state.game.drop_speed() {
-                        let board_update = state.game.act(tetris::Action::SoftDrop);
-                        state.board_updated.merge(board_update);
-                        state.last_move_down = instant;
-                    }
-                }
-            }
+        // Poll joystick first
+        // The axes of the joystick are swapped in `Joystick`, use the center of the other axis
+        // so a failed reading is seen as the joystick being in the center
+        let joystick_x = joystick.adc.read(&mut joystick.pin_x).unwrap_or_else(|_| {
+            ADC_ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+            joystick.handle.center_y()
+        });
+        let joystick_y = joystick.adc.read(&mut joystick.pin_y).unwrap_or_else(|_| {
+            ADC_ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+            joystick.handle.center_x()
+        });
 
-            match mem::take(&mut state.board_updated) {
-                BoardUpdate::None => return,
-                BoardUpdate::Partial(data) => {
-                    for (coord, cell) in data {
-                        display.draw_piece(coord.x, coord.y, cell == Cell::Occured);
-                    }
+        let now = timer.get_counter();
+        let now_ms = now.duration_since_epoch().to_millis();
+        // Outside of a game the directions switch the mode and the option,
+        // only the initial push of the joystick counts there
+        let playing =
+            critical_section::with(|cs| GLOBAL_STATE.borrow(cs).borrow().game.is_playing());
 
-                    display.flush();
-                    return;
+        if let Some(state) = joystick.handle.state_from(joystick_y, joystick_x) {
+            input_handler(Input::Joystick(state));
+            das.press(state, now_ms);
+            last_soft_drop = now;
+        } else if playing && joystick.handle.state() == JoystickState::Down {
+            // Keep soft dropping while the joystick is held down, faster the further it is pushed
+            let magnitude = joystick.handle.magnitude(joystick_y, joystick_x);
+
+            if let Some(duration) = now.checked_duration_since(last_soft_drop) {
+                if duration.to_millis() >= Joystick::soft_drop_arr_ms(magnitude) {
+                    input_handler(Input::Joystick(JoystickState::Down));
+                    last_soft_drop = now;
                 }
-                BoardUpdate::Full => (), // Handle full update below
             }
+        }
 
-            let current_tetromino_blocks = state.game.get_current_tetromino_position();
-
-            match &state.game.state {
-                GameState::New => display.draw_start_screen(),
-                GameState::GameOver { score } => {
-                    display.draw_game_over(*score);
-                    sio.fifo.write(COMMAND_STOP);
-                }
-                GameState::Playing { score, queue, .. } => {
-                    display.draw_board(TETRIS_WIDTH as i16, TETRIS_HEIGHT as i16);
-                    display.draw_score(*score);
+        for input in das.events_since_last_frame(now_ms) {
+            if !playing {
+                continue;
+            }
 
-                    for pixel in state.game.board.iter() {
-                        display.draw_piece(pixel.x, pixel.y, true);
-                    }
+            input_handler(input);
+        }
 
-                    for pixel in current_tetromino_blocks {
-                        display.draw_piece(pixel.x, pixel.y, true);
-                    }
+        let music_progress = link.poll();
 
-                    let next_piece = queue.peek();
-                    let next_piece_blocks = tetris::get_tetromino_blocks(
-                        next_piece,
-                        if matches!(next_piece, Tetromino::I | Tetromino::L | Tetromino::J) {
-                            Rotation::Left
-                        } else {
-                            Rotation::default()
-                        },
-                    );
-
-                    for block in next_piece_blocks {
-                        display.draw_next_piece(block.x, block.y);
-                    }
+        critical_section::with(|cs| {
+            let mut state = GLOBAL_STATE.borrow(cs).borrow_mut();
 
-                    display.flush();
-                    sio.fifo.write(COMMAND_PLAY);
+            GameContext {
+                state: &mut state,
+                renderer: &mut renderer,
+                link: &mut link,
+                timer: &mut timer,
+                #[cfg(feature = "ai")]
+                last_ai_action: &mut last_ai_action,
+            }
+            .tick(now, music_progress);
+
+            #[cfg(feature = "usb-debug")]
+            usb_debug.track(
+                state.game.is_playing(),
+                state.game.get_lines_cleared() as u64,
+                state.game.get_score(),
+            );
+            #[cfg(feature = "usb-debug")]
+            usb_debug.track_overflows(BoardUpdate::<16>::overflow_count());
+
+            #[cfg(all(feature = "usb-debug", feature = "shadow-mode"))]
+            if core::mem::take(&mut state.shadow_placed) {
+                if let Some(shadow) = state.game.shadow() {
+                    usb_debug.send_board("shadow", shadow.board());
                 }
             }
         });
@@ -263,6 +519,83 @@ fn main() -> ! {
     }
 }
 
+/// Everything a frame of the main loop works with, borrowed for the duration of the frame
+struct GameContext<'a, I2C, const SIZE_MUL: i16> {
+    state: &'a mut State,
+    renderer: &'a mut Renderer<I2C, SIZE_MUL>,
+    link: &'a mut SioLink,
+    timer: &'a mut hal::Timer<hal::timer::CopyableTimer0>,
+    #[cfg(feature = "ai")]
+    last_ai_action: &'a mut hal::timer::Instant,
+}
+
+impl<I2C: I2c, const SIZE_MUL: i16> GameContext<'_, I2C, SIZE_MUL> {
+    /// Advances the game to the given time and draws it, `music_progress` is the latest
+    /// progress reported by core 1
+    fn tick(&mut self, now: hal::timer::Instant, music_progress: Option<u8>) {
+        let now_ms = now.duration_since_epoch().to_millis();
+        self.state.gravity_tick(now_ms);
+
+        #[cfg(feature = "ai")]
+        if self.state.option == GameOption::AutoPlay && self.state.game.is_playing() {
+            if let Some(duration) = now.checked_duration_since(*self.last_ai_action) {
+                if duration.to_millis() >= AI_ACTION_DELAY_MS {
+                    let action = self.state.game.ai_suggest_action();
+                    self.state.act(action);
+                    *self.last_ai_action = now;
+                }
+            }
+        }
+
+        self.renderer
+            .render_frame(self.state, self.link, self.timer, music_progress);
+    }
+}
+
+/// Center of the joystick as the median of several readings, so it is not thrown off
+/// by the joystick being moved while the game starts
+fn read_joystick_center<X, Y>(
+    adc: &mut hal::adc::Adc,
+    pin_x: &mut X,
+    pin_y: &mut Y,
+    timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+) -> (u16, u16)
+where
+    hal::adc::Adc: _embedded_hal_adc_OneShot<hal::adc::Adc, u16, X>
+        + _embedded_hal_adc_OneShot<hal::adc::Adc, u16, Y>,
+{
+    let mut samples_x = [0; JOYSTICK_CALIBRATION_SAMPLES];
+    let mut samples_y = [0; JOYSTICK_CALIBRATION_SAMPLES];
+
+    for (x, y) in samples_x.iter_mut().zip(samples_y.iter_mut()) {
+        *x = adc.read(pin_x).unwrap_or(0);
+        *y = adc.read(pin_y).unwrap_or(0);
+        timer.delay_ms(JOYSTICK_CALIBRATION_GAP_MS);
+    }
+
+    (input::median(&mut samples_x), input::median(&mut samples_y))
+}
+
+/// Blinks the onboard LED in the SOS pattern (three short, three long, three short pulses)
+fn blink_sos(
+    led: &mut gpio::Pin<gpio::bank0::Gpio25, gpio::FunctionSioOutput, gpio::PullNone>,
+    timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+) {
+    const SHORT_MS: u32 = 200;
+    const LONG_MS: u32 = 600;
+
+    for pulse in [SHORT_MS, LONG_MS, SHORT_MS] {
+        for _ in 0..3 {
+            led.set_high().unwrap();
+            timer.delay_ms(pulse);
+            led.set_low().unwrap();
+            timer.delay_ms(SHORT_MS);
+        }
+    }
+
+    timer.delay_ms(LONG_MS * 2);
+}
+
 fn input_handler(input: input::Input) {
     static mut TOOLS: Option<InputHandleTools> = None;
 
@@ -285,34 +618,120 @@ fn input_handler(input: input::Input) {
 
     let action = match input {
         Input::JoystickButton => Some(tetris::Action::HardDrop),
+        Input::JoystickButtonLongPress => Some(tetris::Action::Hold),
         Input::Joystick(JoystickState::Center) => None,
         Input::Joystick(JoystickState::Down) => Some(tetris::Action::SoftDrop),
         Input::Joystick(JoystickState::Left) => Some(tetris::Action::MoveLeft),
         Input::Joystick(JoystickState::Right) => Some(tetris::Action::MoveRight),
-        Input::Joystick(JoystickState::TopLeft) => Some(tetris::Action::Rotate),
+        Input::Joystick(JoystickState::TopLeft) => Some(tetris::Action::RotateCCW),
         Input::Joystick(JoystickState::TopRight) => Some(tetris::Action::Rotate),
     };
 
     if let Some(action) = action {
         critical_section::with(move |cs| {
             let mut state = GLOBAL_STATE.borrow(cs).borrow_mut();
-            if !state.game.is_playing() && action == tetris::Action::HardDrop {
-                state.game.start();
+
+            // Left and right switch the option while the game is not running
+            #[cfg(feature = "ai")]
+            if !state.game.is_playing()
+                && matches!(action, tetris::Action::MoveLeft | tetris::Action::MoveRight)
+            {
+                state.option = match state.option {
+                    GameOption::Manual => GameOption::AutoPlay,
+                    GameOption::AutoPlay => GameOption::Manual,
+                };
                 state.board_updated = BoardUpdate::Full;
-                state.last_move_down = tools.timer.get_counter();
+                return;
+            }
+
+            let now_ms = tools.timer.get_counter().duration_since_epoch().to_millis();
+
+            if !state.game.is_playing() && action == tetris::Action::SoftDrop {
+                state.mode = match state.mode {
+                    GameMode::Marathon => GameMode::Sprint {
+                        lines_goal: SPRINT_LINES,
+                    },
+                    GameMode::Sprint { .. } => GameMode::Marathon,
+                };
+                state.board_updated = BoardUpdate::Full;
+            } else if !state.game.is_playing() && action == tetris::Action::HardDrop {
+                state.game.start_with_mode(state.mode, now_ms);
+                state.board_updated = BoardUpdate::Full;
+                state.game.reset_gravity(now_ms);
             } else {
-                let board_update = state.game.act(action);
-                state.board_updated.merge(board_update);
+                state.act(action);
                 if action == tetris::Action::SoftDrop {
-                    state.last_move_down = tools.timer.get_counter();
+                    state.game.reset_gravity(now_ms);
                 }
             }
         });
     }
 }
 
+/// Sound effect played by core 1 next to the melody
+struct EffectPlayer {
+    effect: Option<bgm::Effect>,
+    /// Position of the playing note in the effect
+    index: usize,
+    note_start: hal::timer::Instant,
+}
+
+impl EffectPlayer {
+    fn new() -> Self {
+        Self {
+            effect: None,
+            index: 0,
+            note_start: hal::timer::Instant::from_ticks(0),
+        }
+    }
+
+    fn start<I: SliceId, M: ValidSliceMode<I>>(
+        &mut self,
+        effect: bgm::Effect,
+        pwm: &mut Slice<I, M>,
+        now: hal::timer::Instant,
+    ) {
+        self.effect = Some(effect);
+        self.index = 0;
+        self.note_start = now;
+
+        if let Some((note, _)) = effect.note(0) {
+            play_note(pwm, note, EFFECT_VOLUME);
+        }
+    }
+
+    /// Moves to the next note of the effect when the current one is over
+    fn update<I: SliceId, M: ValidSliceMode<I>>(
+        &mut self,
+        pwm: &mut Slice<I, M>,
+        now: hal::timer::Instant,
+    ) {
+        let Some(effect) = self.effect else {
+            return;
+        };
+
+        let duration = effect.note(self.index).map_or(0, |(_, duration)| duration);
+        let elapsed = now.checked_duration_since(self.note_start);
+        if !elapsed.is_some_and(|elapsed| elapsed.to_millis() >= duration as u64) {
+            return;
+        }
+
+        self.index += 1;
+        self.note_start = now;
+
+        match effect.note(self.index) {
+            Some((note, _)) => play_note(pwm, note, EFFECT_VOLUME),
+            None => {
+                play_note(pwm, bgm::Note::Rest, EFFECT_VOLUME);
+                self.effect = None;
+            }
+        }
+    }
+}
+
 /// Core 1 task to play the background music
-/// This will listen to the command from the main core to play or stop the music
+/// This will listen to the command from the main core to play or stop the music,
+/// the sound effects are played on a second PWM slice so they do not interrupt the music
 fn core1_task(mut timer: hal::Timer<hal::timer::CopyableTimer0>) {
     let mut pac = unsafe { hal::pac::Peripherals::steal() };
     let mut sio = hal::Sio::new(pac.SIO);
@@ -326,27 +745,69 @@ fn core1_task(mut timer: hal::Timer<hal::timer::CopyableTimer0>) {
     // Init PWMs
     let pwm_slices = hal::pwm::Slices::new(pac.PWM, &mut pac.RESETS);
 
-    // Configure PWM4
+    // Configure PWM0 for the melody
     let mut pwm = pwm_slices.pwm0;
     pwm.set_ph_correct();
     pwm.enable();
 
     pwm.channel_b.output_to(pins.gpio1);
 
+    // Configure PWM1 for the sound effects
+    let mut effect_pwm = pwm_slices.pwm1;
+    effect_pwm.set_ph_correct();
+    effect_pwm.enable();
+
+    effect_pwm.channel_b.output_to(pins.gpio3);
+
+    let mut effect = EffectPlayer::new();
+
     loop {
-        if sio.fifo.read_blocking() != COMMAND_PLAY {
-            continue;
+        // Wait for the play command from the main core
+        while poll_command(&mut sio.fifo, &timer, &mut effect_pwm, &mut effect)
+            != Some(SioCommand::Play)
+        {
+            timer.delay_ms(1);
         }
 
-        // Got the play command from the main core
-        for (note, duration) in bgm::melody() {
-            play_note(&mut pwm, note);
-            timer.delay_ms(duration - bgm::SILENT_DURATION);
-            play_note(&mut pwm, bgm::Note::Rest);
-            timer.delay_ms(bgm::SILENT_DURATION);
+        let mut melody = bgm::melody_with_progress();
+        let mut fade_in = true;
 
-            // Check for stop command
-            if sio.fifo.read() == Some(COMMAND_STOP) {
+        while let Some((note, duration)) = melody.next() {
+            let duration = bgm::duration_at_level(duration, MUSIC_LEVEL.load(Ordering::Relaxed));
+
+            if fade_in {
+                play_note(&mut pwm, note, 0);
+                ramp_pwm_volume(&mut pwm, 0, VOLUME, VOLUME_RAMP_STEPS, &mut timer);
+                fade_in = false;
+            } else {
+                play_note(&mut pwm, note, VOLUME);
+            }
+
+            sio.fifo
+                .write(SioCommand::Progress(melody.progress_percent()).into());
+            let stopped = delay_with_effects(
+                &mut sio.fifo,
+                &mut timer,
+                &mut effect_pwm,
+                &mut effect,
+                duration - bgm::SILENT_DURATION,
+            );
+
+            if stopped {
+                ramp_pwm_volume(&mut pwm, VOLUME, 0, VOLUME_RAMP_STEPS, &mut timer);
+            }
+
+            play_note(&mut pwm, bgm::Note::Rest, VOLUME);
+
+            if stopped
+                || delay_with_effects(
+                    &mut sio.fifo,
+                    &mut timer,
+                    &mut effect_pwm,
+                    &mut effect,
+                    bgm::SILENT_DURATION,
+                )
+            {
                 // Got the stop command from the main core
                 break;
             }
@@ -354,37 +815,230 @@ fn core1_task(mut timer: hal::Timer<hal::timer::CopyableTimer0>) {
     }
 }
 
-fn play_note<I: SliceId, M: ValidSliceMode<I>>(pwm: &mut Slice<I, M>, note: bgm::Note) {
-    let frequency = note.frequency();
-    pwm.set_div_int(frequency.clk_div);
-    pwm.set_top(frequency.cnt);
-    pwm.set_counter(0);
-    pwm.channel_b.set_duty_cycle_percent(VOLUME).unwrap();
+/// Handles a command from core 0 if there is any, the sound effects are started
+/// and the level is taken over right away.
+/// Returns the other commands
+fn poll_command<I: SliceId, M: ValidSliceMode<I>>(
+    fifo: &mut hal::sio::SioFifo,
+    timer: &hal::Timer<hal::timer::CopyableTimer0>,
+    effect_pwm: &mut Slice<I, M>,
+    effect: &mut EffectPlayer,
+) -> Option<SioCommand> {
+    let now = timer.get_counter();
+    effect.update(effect_pwm, now);
+
+    let command = fifo.read()?;
+
+    if RELIABLE_FIFO {
+        fifo.write(SioCommand::Ack((command >> SEQUENCE_SHIFT) as u8).into());
+    }
+
+    match SioCommand::from_u32(command & COMMAND_MASK)? {
+        SioCommand::PlayEffect(new_effect) => {
+            effect.start(new_effect, effect_pwm, now);
+            None
+        }
+        SioCommand::SetLevel(level) => {
+            MUSIC_LEVEL.store(level, Ordering::Relaxed);
+            None
+        }
+        command => Some(command),
+    }
 }
 
-#[interrupt]
-fn IO_IRQ_BANK0() {
-    static mut BUTTONS: Option<Buttons> = None;
+/// Waits for the given time while playing the sound effects,
+/// returns true if core 0 asked to stop the music meanwhile
+fn delay_with_effects<I: SliceId, M: ValidSliceMode<I>>(
+    fifo: &mut hal::sio::SioFifo,
+    timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+    effect_pwm: &mut Slice<I, M>,
+    effect: &mut EffectPlayer,
+    ms: u32,
+) -> bool {
+    let start = timer.get_counter();
+    let mut stopped = false;
 
-    if BUTTONS.is_none() {
-        critical_section::with(|cs| {
-            *BUTTONS = GLOBAL_BUTTONS.borrow(cs).take();
-        });
+    loop {
+        stopped |= poll_command(fifo, timer, effect_pwm, effect) == Some(SioCommand::Stop);
+
+        let elapsed = timer.get_counter().checked_duration_since(start);
+        if elapsed.is_some_and(|elapsed| elapsed.to_millis() >= ms as u64) {
+            return stopped;
+        }
+
+        timer.delay_ms(1);
+    }
+}
+
+/// Core 0 end of the SIO FIFO. Commands are sent without waiting for core 1, each with
+/// a sequence number which core 1 echoes in its `Ack`, so a late acknowledgement
+/// is not taken for the one of a newer command
+struct SioLink {
+    fifo: hal::sio::SioFifo,
+    next_sequence: u8,
+    /// Sequence number, command and time it was sent of the commands not acknowledged yet,
+    /// oldest first
+    pending: heapless::Deque<(u8, SioCommand, hal::timer::Instant), MAX_PENDING_COMMANDS>,
+}
+
+impl SioLink {
+    fn new(fifo: hal::sio::SioFifo) -> Self {
+        Self {
+            fifo,
+            next_sequence: 0,
+            pending: heapless::Deque::new(),
+        }
+    }
+
+    /// Sends the command to core 1, `now` is the time its acknowledgement is awaited from
+    fn send(&mut self, command: SioCommand, now: hal::timer::Instant) {
+        let sequence = self.next_sequence;
+        self.next_sequence = sequence.wrapping_add(1);
+        self.fifo
+            .write(u32::from(command) | (sequence as u32) << SEQUENCE_SHIFT);
+
+        if RELIABLE_FIFO {
+            // The oldest command is given up on to make room
+            if self.pending.is_full() {
+                self.pending.pop_front();
+            }
+
+            let _ = self.pending.push_back((sequence, command, now));
+        }
+    }
+
+    /// Reads every message of core 1, returns the latest progress of the music if any.
+    /// Core 1 handles the commands in order, so an acknowledgement covers the older ones too
+    fn poll(&mut self) -> Option<u8> {
+        let mut progress = None;
+
+        while let Some(message) = self.fifo.read() {
+            match SioCommand::from_u32(message) {
+                Some(SioCommand::Progress(percent)) => progress = Some(percent),
+                Some(SioCommand::Ack(sequence)) => {
+                    // Acknowledgement of a command which has already been given up on
+                    if !self
+                        .pending
+                        .iter()
+                        .any(|&(pending, ..)| pending == sequence)
+                    {
+                        continue;
+                    }
+
+                    while let Some((pending, ..)) = self.pending.pop_front() {
+                        if pending == sequence {
+                            break;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        progress
     }
 
-    let Some(buttons) = BUTTONS else {
+    /// Takes the oldest command core 1 has not acknowledged within `timeout_ms`
+    fn take_unacknowledged(
+        &mut self,
+        now: hal::timer::Instant,
+        timeout_ms: u64,
+    ) -> Option<SioCommand> {
+        let &(_, command, sent) = self.pending.front()?;
+
+        let elapsed = now.checked_duration_since(sent)?;
+        if elapsed.to_millis() < timeout_ms {
+            return None;
+        }
+
+        self.pending.pop_front();
+        Some(command)
+    }
+}
+
+fn play_note<I: SliceId, M: ValidSliceMode<I>>(pwm: &mut Slice<I, M>, note: bgm::Note, volume: u8) {
+    let freq = note.frequency();
+    debug_assert!(freq.validate().is_ok());
+    configure_pwm_for_frequency(pwm, &freq, SYSTEM_CLOCK_HZ, volume);
+}
+
+/// Writes the PWM registers for the given frequency,
+/// invalid settings are ignored and the PWM keeps its previous frequency
+fn configure_pwm_for_frequency<I: SliceId, M: ValidSliceMode<I>>(
+    pwm: &mut Slice<I, M>,
+    freq: &bgm::Frequency,
+    sys_clk_hz: u32,
+    volume: u8,
+) {
+    if !freq.is_valid() || pwm_actual_frequency(freq.clk_div, freq.cnt, sys_clk_hz) == 0 {
         return;
-    };
+    }
+
+    pwm.set_div_int(freq.clk_div);
+    pwm.set_top(freq.cnt);
+    pwm.set_counter(0);
+    pwm.channel_b.set_duty_cycle_percent(volume).unwrap();
+}
 
-    let now = buttons.timer.get_counter();
-    let maybe_input = buttons
-        .joystick_btn
-        .interrupted(now)
-        .then_some(Input::JoystickButton);
+/// Changes the duty cycle of the PWM linearly from `from` to `to` percent in the given number
+/// of steps, so the speaker does not click when the music starts or stops
+fn ramp_pwm_volume<I: SliceId, M: ValidSliceMode<I>>(
+    pwm: &mut Slice<I, M>,
+    from: u8,
+    to: u8,
+    steps: u8,
+    timer: &mut impl DelayNs,
+) {
+    let steps = u16::from(steps.max(1));
+
+    for step in 1..=steps {
+        // Percent scaled by the number of steps, to keep the fractions of a percent
+        let duty = u16::from(from) * (steps - step) + u16::from(to) * step;
+        pwm.channel_b
+            .set_duty_cycle_fraction(duty, 100 * steps)
+            .unwrap();
+        timer.delay_ms(VOLUME_RAMP_STEP_MS);
+    }
+}
 
-    if let Some(input) = maybe_input {
-        crate::input_handler(input);
+/// Output frequency in Hz of the PWM with the given register values
+fn pwm_actual_frequency(clk_div: u8, cnt: u16, sys_clk_hz: u32) -> u32 {
+    let period = clk_div as u32 * cnt as u32;
+
+    if period == 0 {
+        return 0;
     }
+
+    sys_clk_hz / period
+}
+
+/// Classifies the press of the joystick button and queues it for the main loop, called by
+/// the interrupt when it is pressed and by the main loop to notice when it is released
+/// or held long enough
+fn poll_button() {
+    let press_kind = critical_section::with(|cs| {
+        let mut buttons = GLOBAL_BUTTONS.borrow(cs).borrow_mut();
+        let buttons = buttons.as_mut()?;
+        let now = buttons.timer.get_counter();
+
+        buttons.joystick_btn.press_kind(now, LONG_PRESS_MS)
+    });
+
+    let input = match press_kind {
+        Some(PressKind::Tap) => Input::JoystickButton,
+        Some(PressKind::LongPress) => Input::JoystickButtonLongPress,
+        None => return,
+    };
+
+    // A press is dropped only when the main loop has not run for several presses
+    critical_section::with(|cs| {
+        let _ = GLOBAL_INPUT_BUFFER.borrow(cs).borrow_mut().push(input);
+    });
+}
+
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    poll_button();
 }
 
 /// Program metadata for `picotool info`
@@ -397,3 +1051,22 @@ pub static PICOTOOL_ENTRIES: [hal::binary_info::EntryAddr; 5] = [
     hal::binary_info::rp_cargo_homepage_url!(),
     hal::binary_info::rp_program_build_attribute!(),
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a4_plays_at_440_hz() {
+        let freq = bgm::Note::A4.frequency();
+        let actual = pwm_actual_frequency(freq.clk_div, freq.cnt, SYSTEM_CLOCK_HZ);
+
+        assert!((439..=441).contains(&actual), "A4 plays at {actual} Hz");
+    }
+
+    #[test]
+    fn invalid_pwm_setting_has_no_frequency() {
+        assert_eq!(pwm_actual_frequency(0, 34091, SYSTEM_CLOCK_HZ), 0);
+        assert_eq!(pwm_actual_frequency(10, 0, SYSTEM_CLOCK_HZ), 0);
+    }
+}
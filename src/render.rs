@@ -0,0 +1,367 @@
+//! Drawing of the game on the display, together with starting and stopping the music
+//! on core 1 to follow the game
+
+use display_interface::DisplayError;
+use embedded_hal::i2c::I2c;
+
+use crate::display::Display;
+use crate::tetris::{BoardUpdate, Cell, GamePhase};
+#[cfg(feature = "ai")]
+use crate::GameOption;
+use crate::{bgm, hal, SioCommand, SioLink, State};
+use crate::{FIFO_ACK_TIMEOUT_MS, TETRIS_HEIGHT, TETRIS_WIDTH};
+
+/// How long the start screen stays before the screensaver starts
+const IDLE_SCREENSAVER_MS: u64 = 60_000;
+const MATRIX_RAIN_FRAME_MS: u64 = 50;
+/// How long a combo, a back-to-back clear and a T-spin are shown over the board
+const COMBO_DISPLAY_MS: u64 = 1000;
+/// The back-to-back label is shown above the combo
+const BACK_TO_BACK_Y: i32 = 54;
+/// The T-spin label is shown above the back-to-back one
+const TSPIN_Y: i32 = 44;
+/// The display flashes this many times on a level up, lit and dark for `LEVEL_UP_FLASH_MS` each
+const LEVEL_UP_FLASHES: u64 = 3;
+const LEVEL_UP_FLASH_MS: u64 = 100;
+/// How long the new level is shown over the board after the flashes
+const LEVEL_UP_TEXT_MS: u64 = 500;
+
+/// Board quality score below which the danger bar is shown, the score of a flat stack
+/// reaching the middle of the board: its height counted twice, less a quarter of its faces
+/// on the top, the floor and both walls
+const DANGER_QUALITY_SCORE: i64 =
+    -((TETRIS_WIDTH * TETRIS_HEIGHT - (2 * TETRIS_WIDTH + TETRIS_HEIGHT) / 4) as i64);
+
+pub struct Renderer<I2C, const SIZE_MUL: i16> {
+    display: Display<I2C, SIZE_MUL>,
+    /// Core 1 has been asked to play the music
+    music_playing: bool,
+    /// Progress of the current music loop in percent, as last reported by core 1
+    music_progress: u8,
+    /// Level of the game when it was last drawn
+    level: u32,
+    /// When the start screen was last drawn, `None` in the other screens
+    idle_since: Option<hal::timer::Instant>,
+    last_rain_frame: hal::timer::Instant,
+    /// Combo, back-to-back and T-spin of the placed piece shown over the board,
+    /// and when it was placed
+    combo_shown: Option<(i32, bool, bool, hal::timer::Instant)>,
+    /// New level shown over the board, and when it was reached
+    level_up_shown: Option<(u32, hal::timer::Instant)>,
+    /// Whether the level up flash was last drawn lit, `None` outside of the flash
+    flash_lit: Option<bool>,
+}
+
+impl<I2C: I2c, const SIZE_MUL: i16> Renderer<I2C, SIZE_MUL> {
+    pub fn new(display: Display<I2C, SIZE_MUL>) -> Self {
+        Self {
+            display,
+            music_playing: false,
+            music_progress: 0,
+            level: 1,
+            idle_since: None,
+            last_rain_frame: hal::timer::Instant::from_ticks(0),
+            combo_shown: None,
+            level_up_shown: None,
+            flash_lit: None,
+        }
+    }
+
+    /// Draws the changes of the game state onto the display, `music_progress` is the latest
+    /// progress reported by core 1. The display is reinitialized after a failed frame
+    /// and the next frame redraws everything
+    pub fn render_frame(
+        &mut self,
+        state: &mut State,
+        link: &mut SioLink,
+        timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+        music_progress: Option<u8>,
+    ) {
+        if let Err(error) = self.draw(state, link, timer, music_progress) {
+            // The display may have lost its configuration, e.g. after a loose wire
+            if matches!(error, DisplayError::BusWriteError) {
+                let _ = self.display.reinit();
+            }
+
+            state.board_updated = BoardUpdate::Full;
+        }
+    }
+
+    fn draw(
+        &mut self,
+        state: &mut State,
+        link: &mut SioLink,
+        timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+        music_progress: Option<u8>,
+    ) -> Result<(), DisplayError> {
+        // Core 1 missed the command, it is sent again with the next full frame
+        while let Some(command) = link.take_unacknowledged(timer.get_counter(), FIFO_ACK_TIMEOUT_MS)
+        {
+            match command {
+                SioCommand::Play => self.music_playing = false,
+                SioCommand::Stop => self.music_playing = true,
+                _ => (),
+            }
+        }
+
+        // The flash covers the whole display, the board is drawn again once it is over
+        if let Some(lit) = self.level_up_flash(timer) {
+            if self.flash_lit != Some(lit) {
+                self.flash_lit = Some(lit);
+                self.display.draw_level_up_flash(lit)?;
+            }
+
+            self.music_progress = music_progress.unwrap_or(self.music_progress);
+            state.board_updated = BoardUpdate::Full;
+            return self.display.end_frame();
+        }
+        self.flash_lit = None;
+
+        if let Some(percent) = music_progress {
+            self.music_progress = percent;
+
+            if state.game.is_playing() {
+                self.display.draw_progress(percent)?;
+                self.display.end_frame()?;
+            }
+        }
+
+        match state.board_updated.take() {
+            BoardUpdate::None => {
+                // The board is redrawn without the overlays once they have been shown long enough
+                let combo_expired = self.combo_expired(timer);
+                let level_up_expired = self.level_up_expired(timer);
+                if !combo_expired && !level_up_expired {
+                    return self.draw_idle(state, timer);
+                }
+
+                if combo_expired {
+                    self.combo_shown = None;
+                }
+                if level_up_expired {
+                    self.level_up_shown = None;
+                }
+            }
+            BoardUpdate::Partial(data) => {
+                let blocks: heapless::Vec<_, 16> = data
+                    .into_iter()
+                    .map(|(coord, cell)| (coord.x, coord.y, cell))
+                    .collect();
+                self.display.draw_pieces_batch(&blocks)?;
+
+                return self.display.end_frame();
+            }
+            BoardUpdate::Full => (), // Handle full update below
+        }
+
+        let t_spin = state.game.take_t_spin().is_some();
+        let cleared_rows = state.game.take_cleared_rows();
+        if !cleared_rows.is_empty() {
+            link.send(
+                SioCommand::PlayEffect(bgm::Effect::LineClear),
+                timer.get_counter(),
+            );
+            self.display
+                .animate_line_clear(&cleared_rows, TETRIS_WIDTH as i16, timer)?;
+        }
+
+        if !cleared_rows.is_empty() || t_spin {
+            if let Some(ps) = state.game.playing_state() {
+                if ps.combo > 0 || ps.back_to_back || t_spin {
+                    self.combo_shown =
+                        Some((ps.combo, ps.back_to_back, t_spin, timer.get_counter()));
+                }
+            }
+        }
+
+        let level = state.game.get_level();
+        if level != self.level {
+            link.send(
+                SioCommand::SetLevel(level.min(u8::MAX as u32) as u8),
+                timer.get_counter(),
+            );
+
+            if state.game.is_playing() && level > self.level {
+                self.level_up_shown = Some((level, timer.get_counter()));
+            }
+        }
+        self.level = level;
+
+        let current_tetromino_blocks = state.game.get_live_piece_blocks();
+
+        self.display.begin_frame();
+        self.idle_since = None;
+
+        match state.game.game_phase() {
+            GamePhase::New => {
+                self.idle_since = Some(timer.get_counter());
+                self.display.draw_start_screen()?;
+                self.display.draw_game_mode(state.mode)?;
+
+                #[cfg(feature = "ai")]
+                if state.option == GameOption::AutoPlay {
+                    self.display.draw_auto_play()?;
+                }
+            }
+            GamePhase::GameOver => {
+                self.display.draw_game_over_with_board(
+                    state.game.get_score(),
+                    state.game.get_mode(),
+                    state.game.get_sprint_time(),
+                    state.game.board(),
+                )?;
+                self.display.draw_game_mode(state.mode)?;
+
+                if self.music_playing {
+                    self.music_playing = false;
+                    link.send(SioCommand::Stop, timer.get_counter());
+                    link.send(
+                        SioCommand::PlayEffect(bgm::Effect::GameOver),
+                        timer.get_counter(),
+                    );
+                    self.display.scroll_long_score(
+                        state.game.get_score(),
+                        state.game.get_mode(),
+                        timer,
+                    )?;
+                }
+            }
+            GamePhase::Playing => {
+                self.display.draw_board_auto(&state.game)?;
+                match state.game.get_remaining_lines() {
+                    Some(lines) => self.display.draw_lines_remaining(lines)?,
+                    None => self.display.draw_score(state.game.get_score())?,
+                }
+                self.display.draw_progress(self.music_progress)?;
+                self.display.draw_danger_bar::<TETRIS_HEIGHT>(
+                    state.game.board().board_quality_score(0) < DANGER_QUALITY_SCORE,
+                )?;
+
+                let mut blocks: heapless::Vec<_, { TETRIS_WIDTH * TETRIS_HEIGHT + 8 }> = state
+                    .game
+                    .board()
+                    .iter()
+                    .map(|(pixel, cell)| (pixel.x, pixel.y, cell))
+                    .collect();
+
+                // Drawn before the live piece, which covers the ghost where they overlap
+                if let Some(ghost) = state.game.get_ghost_position() {
+                    for pixel in ghost {
+                        if !current_tetromino_blocks.contains(&pixel) {
+                            let _ = blocks.push((pixel.x, pixel.y, Cell::Ghost));
+                        }
+                    }
+                }
+
+                if let Some(piece) = state.game.get_piece() {
+                    for pixel in current_tetromino_blocks {
+                        let _ = blocks.push((pixel.x, pixel.y, Cell::Occupied(piece)));
+                    }
+                }
+
+                self.display.draw_pieces_batch(&blocks)?;
+
+                if let Some(next) = state.game.next_piece() {
+                    self.display.draw_next_piece(next)?;
+                }
+
+                if let Some(ps) = state.game.playing_state() {
+                    self.display.draw_hold_piece(ps.held, ps.hold_used)?;
+                }
+
+                if let Some((combo, back_to_back, t_spin, _)) =
+                    self.combo_shown.filter(|_| !self.combo_expired(timer))
+                {
+                    if combo > 0 {
+                        self.display.draw_combo(combo)?;
+                    }
+
+                    if back_to_back {
+                        self.display.draw_overlay_text("B2B", BACK_TO_BACK_Y)?;
+                    }
+
+                    if t_spin {
+                        self.display.draw_overlay_text("T-SPIN", TSPIN_Y)?;
+                    }
+                }
+
+                if let Some((level, _)) = self
+                    .level_up_shown
+                    .filter(|_| !self.level_up_expired(timer))
+                {
+                    self.display.draw_level_up(level)?;
+                }
+
+                if !self.music_playing {
+                    self.music_playing = true;
+                    link.send(SioCommand::Play, timer.get_counter());
+                }
+            }
+        }
+
+        self.display.end_frame()
+    }
+
+    /// The combo, the back-to-back and the T-spin have been shown for `COMBO_DISPLAY_MS`
+    fn combo_expired(&self, timer: &hal::Timer<hal::timer::CopyableTimer0>) -> bool {
+        self.combo_shown.is_some_and(|(_, _, _, since)| {
+            timer
+                .get_counter()
+                .checked_duration_since(since)
+                .is_some_and(|duration| duration.to_millis() >= COMBO_DISPLAY_MS)
+        })
+    }
+
+    /// Whether the display is lit in the current step of the level up flash,
+    /// `None` when no flash is running
+    fn level_up_flash(&self, timer: &hal::Timer<hal::timer::CopyableTimer0>) -> Option<bool> {
+        let (_, since) = self.level_up_shown?;
+        let elapsed = timer.get_counter().checked_duration_since(since)?;
+        let step = elapsed.to_millis() / LEVEL_UP_FLASH_MS;
+
+        (step < LEVEL_UP_FLASHES * 2).then_some(step.is_multiple_of(2))
+    }
+
+    /// The new level has been shown for `LEVEL_UP_TEXT_MS` after the flash
+    fn level_up_expired(&self, timer: &hal::Timer<hal::timer::CopyableTimer0>) -> bool {
+        self.level_up_shown.is_some_and(|(_, since)| {
+            timer
+                .get_counter()
+                .checked_duration_since(since)
+                .is_some_and(|duration| {
+                    duration.to_millis()
+                        >= LEVEL_UP_FLASHES * 2 * LEVEL_UP_FLASH_MS + LEVEL_UP_TEXT_MS
+                })
+        })
+    }
+
+    /// Draws a frame of the screensaver once the start screen has been left alone for a while
+    fn draw_idle(
+        &mut self,
+        state: &mut State,
+        timer: &mut hal::Timer<hal::timer::CopyableTimer0>,
+    ) -> Result<(), DisplayError> {
+        let Some(idle_since) = self.idle_since else {
+            return Ok(());
+        };
+
+        let now = timer.get_counter();
+        let elapsed_ms = |since| {
+            now.checked_duration_since(since)
+                .map_or(0, |duration| duration.to_millis())
+        };
+
+        if elapsed_ms(idle_since) < IDLE_SCREENSAVER_MS
+            || elapsed_ms(self.last_rain_frame) < MATRIX_RAIN_FRAME_MS
+        {
+            return Ok(());
+        }
+
+        let Some(rng) = state.game.rng_mut() else {
+            return Ok(());
+        };
+
+        self.last_rain_frame = now;
+        self.display.draw_matrix_rain_frame(rng)
+    }
+}
@@ -1,3 +1,19 @@
+//! Game logic of Tetris, independent of the hardware.
+//!
+//! The size of the board is given by the const generics `C` (columns) and `R` (rows)
+//! of `Tetris`, which are checked at compile time:
+//! - the board must be at least 4 columns wide and 8 rows tall
+//! - the width must be even so pieces spawn in the center
+//! - the board must not have more than 400 cells to keep it on the stack
+
+use core::fmt;
+#[cfg(feature = "async")]
+use core::future::Future;
+#[cfg(feature = "async")]
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, Ordering};
+#[cfg(feature = "async")]
+use core::task::{Context, Poll};
 use heapless::Vec;
 use rand::prelude::*;
 
@@ -10,7 +26,16 @@ pub struct Coordination {
     pub y: i16,
 }
 
-#[derive(Clone, Copy)]
+impl Coordination {
+    /// Number of single steps between the coordinations, breaks the ties of the AI placements
+    #[cfg(feature = "ai")]
+    pub fn manhattan_distance(&self, other: &Coordination) -> i32 {
+        (self.x as i32 - other.x as i32).abs() + (self.y as i32 - other.y as i32).abs()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(test), derive(defmt::Format))]
 pub enum Tetromino {
     L,
     J,
@@ -21,7 +46,86 @@ pub enum Tetromino {
     I,
 }
 
-#[derive(Default, Clone, Copy)]
+impl Tetromino {
+    /// Index used to pick the rendering style of the piece
+    #[inline]
+    pub fn color_index(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Offset at which the piece spawns in its default rotation on a board with the given
+    /// number of columns, centered with its top block on the first row. Pieces 3 cells wide
+    /// lean to the left as in the guideline
+    pub fn spawn_offset(&self, columns: usize) -> Coordination {
+        let blocks = get_tetromino_blocks(*self, Rotation::Default);
+
+        let min_x = blocks.iter().map(|block| block.x).min().unwrap_or(0);
+        let max_x = blocks.iter().map(|block| block.x).max().unwrap_or(0);
+        let min_y = blocks.iter().map(|block| block.y).min().unwrap_or(0);
+        let width = max_x - min_x + 1;
+
+        Coordination {
+            x: (columns as i16 - width) / 2 - min_x,
+            y: -min_y,
+        }
+    }
+
+    /// Offsets tried in order when rotating the piece from `from` to `to`, from the SRS
+    /// wall kick tables, with the y axis pointing up as in the guideline
+    pub fn wall_kick_offsets(&self, from: Rotation, to: Rotation) -> &'static [(i16, i16); 5] {
+        let from = self.srs_state(from);
+        let to = self.srs_state(to);
+
+        let index = match (to + 4 - from) % 4 {
+            1 => from * 2,
+            3 => from * 2 + 1,
+            // Only a quarter turn kicks
+            _ => return &NO_KICKS,
+        };
+
+        match self {
+            Tetromino::O => &NO_KICKS,
+            Tetromino::I => &I_KICKS[index],
+            _ => &JLSTZ_KICKS[index],
+        }
+    }
+
+    /// State of the SRS tables, 0, R, 2 and L as 0 to 3, the piece is in with the given
+    /// rotation. The J, L and I pieces spawn standing up, in the state SRS calls L
+    fn srs_state(&self, rotation: Rotation) -> usize {
+        let spawn_state = match self {
+            Tetromino::J | Tetromino::L | Tetromino::I => 3,
+            _ => 0,
+        };
+
+        (rotation as usize + spawn_state) % 4
+    }
+}
+
+impl From<Tetromino> for u8 {
+    fn from(piece: Tetromino) -> u8 {
+        piece as u8
+    }
+}
+
+impl TryFrom<u8> for Tetromino {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::L),
+            1 => Ok(Self::J),
+            2 => Ok(Self::T),
+            3 => Ok(Self::O),
+            4 => Ok(Self::Z),
+            5 => Ok(Self::S),
+            6 => Ok(Self::I),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
 pub enum Rotation {
     #[default]
     Default,
@@ -30,13 +134,119 @@ pub enum Rotation {
     Right,
 }
 
+impl Rotation {
+    /// Rotation after a clockwise turn
+    pub fn next_cw(self) -> Rotation {
+        match self {
+            Rotation::Default => Rotation::Left,
+            Rotation::Left => Rotation::Flipped,
+            Rotation::Flipped => Rotation::Right,
+            Rotation::Right => Rotation::Default,
+        }
+    }
+
+    /// Rotation after a counterclockwise turn
+    pub fn next_ccw(self) -> Rotation {
+        match self {
+            Rotation::Default => Rotation::Right,
+            Rotation::Right => Rotation::Flipped,
+            Rotation::Flipped => Rotation::Left,
+            Rotation::Left => Rotation::Default,
+        }
+    }
+}
+
+impl From<Rotation> for u8 {
+    fn from(rotation: Rotation) -> u8 {
+        rotation as u8
+    }
+}
+
+impl TryFrom<u8> for Rotation {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::Default),
+            1 => Ok(Self::Left),
+            2 => Ok(Self::Flipped),
+            3 => Ok(Self::Right),
+            _ => Err(()),
+        }
+    }
+}
+
+const NO_KICKS: [(i16, i16); 5] = [(0, 0); 5];
+
+/// SRS kicks of the J, L, S, T and Z pieces, the clockwise turn out of every SRS state followed
+/// by the counterclockwise one, see `Tetromino::srs_state`
+const JLSTZ_KICKS: [[(i16, i16); 5]; 8] = [
+    [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 0 -> R
+    [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],    // 0 -> L
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],     // R -> 2
+    [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],     // R -> 0
+    [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],    // 2 -> L
+    [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)], // 2 -> R
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],  // L -> 0
+    [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],  // L -> 2
+];
+
+/// SRS kicks of the I piece, in the same order as `JLSTZ_KICKS`
+const I_KICKS: [[(i16, i16); 5]; 8] = [
+    [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)], // 0 -> R
+    [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)], // 0 -> L
+    [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)], // R -> 2
+    [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)], // R -> 0
+    [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)], // 2 -> L
+    [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)], // 2 -> R
+    [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)], // L -> 0
+    [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)], // L -> 2
+];
+
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub enum Cell {
-    Occured,
+    Occupied(Tetromino),
+    /// Outline of the falling piece where it would land, never stored on the board
+    Ghost,
     #[default]
     Empty,
 }
 
+impl Cell {
+    #[inline]
+    pub fn is_occupied(&self) -> bool {
+        matches!(self, Cell::Occupied(_))
+    }
+
+    /// 5x5 fill pattern of a block of the piece, so the pieces can be told apart
+    /// on a monochrome display. `true` is a lit pixel
+    pub fn render_pattern(t: Tetromino) -> [[bool; 5]; 5] {
+        const X: bool = true;
+        const O: bool = false;
+
+        match t.color_index() % 3 {
+            // Solid
+            0 => [[X; 5]; 5],
+            // Dashed
+            1 => [
+                [X, X, X, X, X],
+                [O, O, O, O, O],
+                [X, X, X, X, X],
+                [O, O, O, O, O],
+                [X, X, X, X, X],
+            ],
+            // Cross-hatch
+            _ => [
+                [X, O, X, O, X],
+                [O, X, O, X, O],
+                [X, O, X, O, X],
+                [O, X, O, X, O],
+                [X, O, X, O, X],
+            ],
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum Action {
     MoveLeft,
@@ -44,42 +254,366 @@ pub enum Action {
     SoftDrop,
     HardDrop,
     Rotate,
+    /// Puts the falling piece aside and continues with the held one, or with the next piece
+    /// when none is held. Allowed once until the piece is placed, a hold after that
+    /// is applied to the next piece when it spawns
+    Hold,
+    /// Counterclockwise turn, `Rotate` turns clockwise
+    RotateCCW,
+}
+
+/// One byte per action, e.g. for sending the actions between the cores
+impl From<Action> for u8 {
+    fn from(action: Action) -> u8 {
+        action as u8
+    }
+}
+
+impl TryFrom<u8> for Action {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Self::MoveLeft),
+            1 => Ok(Self::MoveRight),
+            2 => Ok(Self::SoftDrop),
+            3 => Ok(Self::HardDrop),
+            4 => Ok(Self::Rotate),
+            5 => Ok(Self::Hold),
+            6 => Ok(Self::RotateCCW),
+            _ => Err(()),
+        }
+    }
 }
 
+/// Number of partial updates merged into a full update because they ran out of capacity,
+/// shared by all the sizes of `BoardUpdate`
+static PARTIAL_OVERFLOW_COUNT: AtomicU32 = AtomicU32::new(0);
+
 #[derive(Default, PartialEq)]
 pub enum BoardUpdate<const N: usize> {
     Full,
-    Partial(Vec<(Coordination, Cell), N>),
+    Partial(PartialData<N>),
     #[default]
     None,
 }
 
-pub enum State {
+/// Cells changed by a partial board update
+#[cfg(not(feature = "no-heapless-vec"))]
+pub type PartialData<const N: usize> = Vec<(Coordination, Cell), N>;
+#[cfg(feature = "no-heapless-vec")]
+pub type PartialData<const N: usize> = PartialUpdate<N>;
+
+/// Fixed size list of the changed cells, a replacement of `heapless::Vec` for `BoardUpdate`.
+///
+/// Merging 16 single cell updates into it on the host with `-O2` took about the same time
+/// as with `heapless::Vec`, both lists are dominated by the linear search for duplicates
+#[cfg(feature = "no-heapless-vec")]
+pub struct PartialUpdate<const N: usize> {
+    data: [(Coordination, Cell); N],
+    count: u8,
+}
+
+#[derive(Clone)]
+enum State {
     New,
-    Playing {
-        piece: Tetromino,
-        rotation: Rotation,
-        offset: Coordination,
-        queue: TetrominoQueue,
-        score: u64,
-    },
+    Playing(PlayingState),
+    /// `time_ms` is the time of a finished sprint, `None` when the game was topped out
     GameOver {
         score: u64,
+        lines: u64,
+        time_ms: Option<u64>,
     },
 }
 
+/// Rules of the game, chosen when it starts
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    /// Endless game which gets faster with the level
+    #[default]
+    Marathon,
+    /// Race to clear `lines_goal` lines, ranked by the time it took
+    Sprint { lines_goal: u32 },
+}
+
+/// Phase of the game, without the details of its state
+#[derive(Clone, Copy, PartialEq)]
+pub enum GamePhase {
+    New,
+    Playing,
+    GameOver,
+}
+
+impl State {
+    fn phase(&self) -> GamePhase {
+        match self {
+            State::New => GamePhase::New,
+            State::Playing(_) => GamePhase::Playing,
+            State::GameOver { .. } => GamePhase::GameOver,
+        }
+    }
+}
+
+/// Number of lines cleared by a single piece
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClearKind {
+    Single,
+    Double,
+    Triple,
+    Tetris,
+}
+
+impl ClearKind {
+    pub fn from_lines(lines: u8) -> Option<Self> {
+        match lines {
+            1 => Some(Self::Single),
+            2 => Some(Self::Double),
+            3 => Some(Self::Triple),
+            4 => Some(Self::Tetris),
+            _ => None,
+        }
+    }
+
+    /// Points of the clear at the given level
+    pub fn points(self, level: u32) -> u64 {
+        let points = match self {
+            Self::Single => 100,
+            Self::Double => 300,
+            Self::Triple => 500,
+            Self::Tetris => 800,
+        };
+
+        points * level as u64
+    }
+}
+
+/// T-spin by the 3-corner rule, the T piece was rotated into a spot with at least three
+/// of the corners around its center filled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TSpin {
+    /// Both corners on the pointing side of the T are filled
+    Full,
+    /// Only one of the corners on the pointing side is filled
+    Mini,
+}
+
+impl TSpin {
+    /// Bonus points of the T-spin clearing the given number of lines at the given level
+    pub fn bonus(self, lines: u8, level: u32) -> u64 {
+        let points = match (self, lines) {
+            (Self::Full, 1) => 800,
+            (Self::Full, 2) => 1200,
+            (Self::Full, 3) => 1600,
+            (Self::Mini, 1) => 200,
+            (Self::Mini, 2) => 400,
+            _ => 0,
+        };
+
+        points * level as u64
+    }
+}
+
+/// Number of soft drops in a row of the player before each of them earns an extra point
+const SOFT_DROP_BONUS_AFTER: u8 = 3;
+/// Time a piece resting on the stack can still be moved before it is locked
+const LOCK_DELAY_MS: u64 = 200;
+/// Number of moves and rotations which restart the lock delay, the piece locks
+/// right away once they are used up
+const MAX_LOCK_RESETS: u8 = 15;
+
+/// Number of line clears kept in `GameStats::recent_clears`
+const RECENT_CLEARS: usize = 16;
+
+/// Counters of the current game, kept after it is over for the analysis of the game
+#[derive(Clone)]
+pub struct GameStats {
+    /// Number of placed pieces
+    pub pieces: u32,
+    pub singles: u32,
+    pub doubles: u32,
+    pub triples: u32,
+    pub tetrises: u32,
+    /// Number of the piece and kind of the last line clears, oldest first
+    pub recent_clears: Vec<(u32, ClearKind), RECENT_CLEARS>,
+}
+
+impl GameStats {
+    pub const fn new() -> Self {
+        Self {
+            pieces: 0,
+            singles: 0,
+            doubles: 0,
+            triples: 0,
+            tetrises: 0,
+            recent_clears: Vec::new(),
+        }
+    }
+
+    /// Counts a line clear by the piece with the given number
+    pub fn record_clear(&mut self, piece_number: u32, kind: ClearKind) {
+        let counter = match kind {
+            ClearKind::Single => &mut self.singles,
+            ClearKind::Double => &mut self.doubles,
+            ClearKind::Triple => &mut self.triples,
+            ClearKind::Tetris => &mut self.tetrises,
+        };
+        *counter += 1;
+
+        if self.recent_clears.is_full() {
+            self.recent_clears.remove(0);
+        }
+        let _ = self.recent_clears.push((piece_number, kind));
+    }
+
+    /// Percentage of the line clears which were Tetrises
+    pub fn tetris_rate(&self) -> u8 {
+        let clears = self.singles + self.doubles + self.triples + self.tetrises;
+        (self.tetrises * 100 / clears.max(1)) as u8
+    }
+}
+
+#[derive(Clone)]
+pub struct PlayingState {
+    pub piece: Tetromino,
+    pub rotation: Rotation,
+    pub offset: Coordination,
+    pub queue: TetrominoQueue,
+    /// Piece put aside by the player with `Action::Hold`
+    pub held: Option<Tetromino>,
+    /// The current piece came from the hold, so it cannot be held again
+    pub hold_used: bool,
+    /// Hold asked for while it was not allowed, the next piece goes to the hold
+    /// as soon as it spawns, the initial hold system
+    pub initial_hold: bool,
+    /// Points for the cleared lines and for the cells the player dropped the pieces by
+    pub score: u64,
+    pub lines: u64,
+    /// Soft drops of the player in a row without any other action in between
+    pub consecutive_soft_drops: u8,
+    /// Time of the last drop of the piece in milliseconds, for `Tetris::gravity_tick`
+    pub last_drop_ms: u64,
+    /// Time in milliseconds at which the piece came to rest on the stack
+    pub lock_start_ms: Option<u64>,
+    /// Number of times the lock delay of the piece has been restarted
+    pub lock_resets: u8,
+    /// The last move of the piece was a rotation, for the T-spin detection
+    pub last_action_was_rotate: bool,
+    /// Number of pieces in a row which cleared lines after the first one,
+    /// -1 when the last piece cleared nothing
+    pub combo: i32,
+    /// The last placed piece made a difficult clear, a Tetris or a T-spin
+    pub last_was_difficult: bool,
+    /// The last clear was a difficult one right after another and earned the bonus
+    pub back_to_back: bool,
+    /// Time in milliseconds at which the game started
+    pub start_ms: u64,
+    /// Time in milliseconds the game has been played for, until the last `Tetris::gravity_tick`
+    pub elapsed_ms: u64,
+}
+
+impl PlayingState {
+    /// Blocks of the current piece relative to its offset
+    pub fn blocks(&self) -> TetrominoBlocks {
+        get_tetromino_blocks(self.piece, self.rotation)
+    }
+
+    /// Moves the piece to the new offset if the blocks fit in the board there
+    pub fn apply_offset_if_valid<const C: usize, const R: usize>(
+        &mut self,
+        new_offset: Coordination,
+        blocks: TetrominoBlocks,
+        board: &Board<C, R>,
+    ) -> bool {
+        if !board.can_move_in(blocks, new_offset) {
+            return false;
+        }
+
+        self.offset = new_offset;
+        true
+    }
+}
+
+/// Lookup table of the CRC-16/CCITT polynomial 0x1021, used by `Board::checksum`
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+};
+
+#[derive(Clone)]
 pub struct Board<const C: usize, const R: usize> {
     inner: [[Cell; C]; R],
+    /// Occupied cells of each row as bits, kept in sync with `inner`
+    #[cfg(feature = "bitmask-board")]
+    bits: [u32; R],
 }
 
 impl<const C: usize, const R: usize> Board<C, R> {
+    /// Evaluated at compile time when the board is created,
+    /// the bits above the last column are used as the right wall
+    #[cfg(feature = "bitmask-board")]
+    const BITBOARD_CHECK: () = assert!(C < 32, "board too wide for the bitmask board");
+
     const fn new() -> Self {
+        #[cfg(feature = "bitmask-board")]
+        let () = Self::BITBOARD_CHECK;
+
         Self {
             inner: [[Cell::Empty; C]; R],
+            #[cfg(feature = "bitmask-board")]
+            bits: [!((1 << C) - 1); R],
+        }
+    }
+
+    /// CRC-16/CCITT of the cells, one byte per cell, to notice a corrupted board
+    pub const fn checksum(&self) -> u16 {
+        let mut crc: u16 = 0xFFFF;
+        let mut y = 0;
+
+        while y < R {
+            let mut x = 0;
+
+            while x < C {
+                let byte = match self.inner[y][x] {
+                    Cell::Empty | Cell::Ghost => 0,
+                    Cell::Occupied(piece) => 2 + piece as u8,
+                };
+                crc = (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize];
+                x += 1;
+            }
+
+            y += 1;
         }
+
+        crc
     }
 
-    fn place(&mut self, blocks: TetrominoBlocks, offset: Coordination) -> u8 {
+    /// Places the blocks on the board and clears the filled lines,
+    /// returns the number of cleared lines and their indices before the clearing
+    fn place(
+        &mut self,
+        blocks: TetrominoBlocks,
+        offset: Coordination,
+        piece: Tetromino,
+    ) -> (u8, Vec<usize, 4>) {
         for block in blocks {
             let x = block.x + offset.x;
             let y = block.y + offset.y;
@@ -88,362 +622,1678 @@ impl<const C: usize, const R: usize> Board<C, R> {
                 continue;
             }
 
-            self.inner[y as usize][x as usize] = Cell::Occured;
+            self.set_cell(x as usize, y as usize, Cell::Occupied(piece));
         }
 
         self.clear_full_lines()
     }
 
-    fn clear_full_lines(&mut self) -> u8 {
+    /// Returns the number of cleared lines and their indices in descending order
+    fn clear_full_lines(&mut self) -> (u8, Vec<usize, 4>) {
         let mut new_board: [[Cell; C]; R] = [[Cell::Empty; C]; R];
         let mut new_board_line_index = R - 1;
         let mut removed_count = 0;
+        let mut removed_lines = Vec::new();
+        #[cfg(feature = "bitmask-board")]
+        let mut new_bits = [!((1 << C) - 1); R];
 
         // Copy the lines from current board to new Board, ignoring fully filled lines.
         for line_index in (0..R).rev() {
-            if self.inner[line_index].iter().all(|&v| v == Cell::Occured) {
+            if self.is_row_full(line_index) {
                 removed_count += 1;
+                // A single piece cannot fill more than 4 lines
+                let _ = removed_lines.push(line_index);
                 continue;
             }
 
             new_board[new_board_line_index] = self.inner[line_index];
-            new_board_line_index -= 1;
+            #[cfg(feature = "bitmask-board")]
+            {
+                new_bits[new_board_line_index] = self.bits[line_index];
+            }
+            // Saturating as the top row is copied last
+            new_board_line_index = new_board_line_index.saturating_sub(1);
         }
 
         self.inner = new_board;
-        removed_count
-    }
-
-    fn wall_bounce_offset_modifier(&self, blocks: TetrominoBlocks, offset: Coordination) -> i16 {
-        let mut modifier = 0;
-
-        for block in blocks {
-            let x = block.x + offset.x;
 
-            if x < 0 {
-                modifier = modifier.max(-x);
-            } else if x >= C as i16 {
-                modifier = modifier.min(C as i16 - x - 1);
-            }
+        #[cfg(feature = "bitmask-board")]
+        {
+            self.bits = new_bits;
         }
 
-        modifier
+        (removed_count, removed_lines)
     }
 
-    fn can_move_in(&self, blocks: TetrominoBlocks, offset: Coordination) -> bool {
-        for block in blocks {
-            let x = block.x + offset.x;
-            let y = block.y + offset.y;
+    /// Cell at the given column and row, `None` when it is outside of the board
+    pub fn get_cell(&self, x: usize, y: usize) -> Option<Cell> {
+        self.inner.get(y).and_then(|row| row.get(x)).copied()
+    }
 
-            // Ignore hidden pieces on the top
-            if y < 0 {
-                continue;
-            }
+    /// Overwrites the cell at the given column and row, returns false when it is outside of the board
+    pub fn set_cell(&mut self, x: usize, y: usize, cell: Cell) -> bool {
+        let Some(target) = self.inner.get_mut(y).and_then(|row| row.get_mut(x)) else {
+            return false;
+        };
 
-            if y >= R as i16 || x < 0 || x >= C as i16 {
-                return false;
-            }
+        *target = cell;
 
-            if self.inner[y as usize][x as usize] == Cell::Occured {
-                return false;
-            }
+        #[cfg(feature = "bitmask-board")]
+        {
+            self.bits[y] = self.row_bits(y);
         }
 
         true
     }
 
-    pub fn iter(&self) -> BoardIter<'_, C, R> {
-        BoardIter {
-            board: self,
-            current_coor: Coordination { x: 0, y: 0 },
-        }
-    }
-}
-
-pub struct BoardIter<'a, const C: usize, const R: usize> {
-    board: &'a Board<C, R>,
-    current_coor: Coordination,
-}
+    /// Exchanges two cells, returns false without changing the board when either of them is
+    /// outside of the board
+    #[cfg(test)]
+    pub fn swap_cell(&mut self, x1: usize, y1: usize, x2: usize, y2: usize) -> bool {
+        let (Some(first), Some(second)) = (self.get_cell(x1, y1), self.get_cell(x2, y2)) else {
+            return false;
+        };
 
-impl<'a, const COL: usize, const ROW: usize> Iterator for BoardIter<'a, COL, ROW> {
-    type Item = Coordination;
+        self.inner[y1][x1] = second;
+        self.inner[y2][x2] = first;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut coor = self.current_coor;
+        #[cfg(feature = "bitmask-board")]
+        {
+            self.bits[y1] = self.row_bits(y1);
+            self.bits[y2] = self.row_bits(y2);
+        }
 
-        while (coor.x as usize) < COL && (coor.y as usize) < ROW {
-            self.current_coor.x += 1;
+        true
+    }
 
-            if self.current_coor.x as usize >= COL {
-                self.current_coor.x = 0;
-                self.current_coor.y += 1;
-            }
+    /// Board with every row reversed, column 0 swapped with column `C - 1`
+    #[cfg(test)]
+    pub fn mirror_horizontal(&self) -> Board<C, R> {
+        let mut board = self.clone();
 
-            if self.board.inner[coor.y as usize][coor.x as usize] == Cell::Occured {
-                return Some(coor);
+        for y in 0..R {
+            for x in 0..C / 2 {
+                board.swap_cell(x, y, C - 1 - x, y);
             }
-
-            coor = self.current_coor;
         }
 
-        None
+        board
     }
-}
 
-pub struct TetrominoQueue {
-    queue: Vec<Tetromino, 7>,
-}
-
-impl TetrominoQueue {
-    fn new() -> Self {
-        Self { queue: Vec::new() }
+    /// Board with the order of the rows reversed
+    #[cfg(test)]
+    pub fn mirror_vertical(&self) -> Board<C, R> {
+        Self::from_cells(core::array::from_fn(|y| self.inner[R - 1 - y]))
     }
 
-    fn init(&mut self, rng: &mut impl Rng) {
-        let _ = self.queue.extend_from_slice(&[
-            Tetromino::J,
-            Tetromino::L,
-            Tetromino::S,
-            Tetromino::Z,
-            Tetromino::T,
-            Tetromino::O,
-            Tetromino::I,
-        ]);
-
-        self.queue.shuffle(rng);
+    /// Board rotated clockwise, the bottom row becomes the first column
+    #[cfg(test)]
+    pub fn rotate_90(&self) -> Board<R, C> {
+        Board::from_cells(core::array::from_fn(|y| {
+            core::array::from_fn(|x| self.inner[R - 1 - x][y])
+        }))
     }
 
-    fn next(&mut self, rng: &mut impl Rng) -> Tetromino {
-        let result = self.queue.pop().unwrap();
+    #[cfg(test)]
+    fn from_cells(inner: [[Cell; C]; R]) -> Self {
+        let mut board = Self::new();
+        board.inner = inner;
 
-        if self.queue.is_empty() {
-            self.init(rng);
+        #[cfg(feature = "bitmask-board")]
+        {
+            board.bits = board.to_bitboard();
         }
 
-        result
+        board
     }
 
-    pub fn peek(&self) -> Tetromino {
-        *self.queue.last().unwrap()
+    pub fn is_row_full(&self, row: usize) -> bool {
+        self.inner
+            .get(row)
+            .is_some_and(|line| line.iter().all(Cell::is_occupied))
     }
-}
 
-pub struct Tetris<const C: usize, const R: usize, Rng: RngCore> {
-    pub board: Board<C, R>,
-    pub state: State,
-    rng: Option<Rng>,
-}
+    /// Horizontal offset which pushes the blocks back inside the walls,
+    /// `None` when the piece is wider than the board
+    fn compute_wall_kick_x_offset(
+        &self,
+        blocks: TetrominoBlocks,
+        offset: Coordination,
+    ) -> Option<i16> {
+        let min_x = blocks.iter().map(|block| block.x + offset.x).min()?;
+        let max_x = blocks.iter().map(|block| block.x + offset.x).max()?;
 
-impl<const C: usize, const R: usize, Rng: RngCore> Tetris<C, R, Rng> {
-    pub const fn new() -> Self {
-        Self {
-            board: Board::new(),
-            state: State::New,
-            rng: None,
+        if max_x - min_x >= C as i16 {
+            return None;
         }
-    }
 
-    pub fn set_rng(&mut self, rng: Rng) {
-        self.rng = Some(rng);
+        if min_x < 0 {
+            Some(-min_x)
+        } else if max_x >= C as i16 {
+            Some(C as i16 - max_x - 1)
+        } else {
+            Some(0)
+        }
     }
 
-    pub fn is_playing(&self) -> bool {
-        matches!(self.state, State::Playing { .. })
+    /// Vertical offset which pushes the blocks down below the top of the board
+    fn compute_ceiling_kick_y_offset(&self, blocks: TetrominoBlocks, offset: Coordination) -> i16 {
+        let min_y = blocks
+            .iter()
+            .map(|block| block.y + offset.y)
+            .min()
+            .unwrap_or(0);
+
+        (-min_y).max(0)
     }
 
-    pub fn start(&mut self) {
-        if self.is_playing() || self.rng.is_none() {
-            return;
+    fn is_occupied(&self, coord: Coordination) -> bool {
+        if coord.x < 0 || coord.y < 0 {
+            return false;
         }
 
-        let mut queue = TetrominoQueue::new();
-        self.board = Board::new();
-        queue.init(self.rng.as_mut().unwrap());
+        self.get_cell(coord.x as usize, coord.y as usize)
+            .is_some_and(|cell| cell.is_occupied())
+    }
 
-        self.state = State::Playing {
-            piece: Tetromino::J,
-            rotation: Rotation::Default,
-            score: 0,
-            offset: Coordination { x: 5, y: 0 },
-            queue,
+    /// T-spin of a T piece at the offset in the given rotation by the 3-corner rule,
+    /// the walls and the floor count as filled corners
+    pub fn t_spin(&self, offset: Coordination, rotation: Rotation) -> Option<TSpin> {
+        // Corners around the center of the T at (1, 1), the two on its pointing side first
+        let corners = match rotation {
+            Rotation::Default => [(0, 0), (2, 0), (0, 2), (2, 2)],
+            Rotation::Left => [(2, 0), (2, 2), (0, 0), (0, 2)],
+            Rotation::Flipped => [(0, 2), (2, 2), (0, 0), (2, 0)],
+            Rotation::Right => [(0, 0), (0, 2), (2, 0), (2, 2)],
         };
 
-        self.spawn_new_piece();
-    }
+        let filled = corners.map(|(x, y)| {
+            let corner = Coordination {
+                x: offset.x + x,
+                y: offset.y + y,
+            };
 
-    /// Drop speed in milliseconds
-    /// Hard code 3 seconds for now
-    #[inline]
-    pub fn drop_speed(&self) -> u64 {
-        1000
-    }
+            corner.x < 0 || corner.x >= C as i16 || corner.y >= R as i16 || self.is_occupied(corner)
+        });
 
-    pub fn get_current_tetromino_position(&self) -> TetrominoBlocks {
-        if let State::Playing {
-            piece,
-            rotation,
-            offset,
-            ..
-        } = self.state
-        {
-            get_tetromino_blocks(piece, rotation).map(|block| Coordination {
-                x: block.x + offset.x,
-                y: block.y + offset.y,
-            })
-        } else {
-            [Coordination::default(); 4]
+        match filled.iter().filter(|&&filled| filled).count() {
+            0..=2 => None,
+            _ if filled[0] && filled[1] => Some(TSpin::Full),
+            _ => Some(TSpin::Mini),
         }
     }
 
-    fn spawn_new_piece(&mut self) {
-        let mut is_gameover: Option<State> = None;
-
-        if let State::Playing {
-            ref mut piece,
-            ref mut rotation,
-            ref mut offset,
-            ref mut queue,
-            score,
-            ..
-        } = self.state
-        {
-            *rotation = Rotation::Default;
-            *offset = Coordination {
-                x: (C / 2) as i16,
-                y: 0,
-            };
+    #[cfg(feature = "bitmask-board")]
+    fn can_move_in(&self, blocks: TetrominoBlocks, offset: Coordination) -> bool {
+        self.can_move_in_bitmask(&blocks.to_bitmask_at(offset, C), offset.y)
+    }
+
+    #[cfg(not(feature = "bitmask-board"))]
+    fn can_move_in(&self, blocks: TetrominoBlocks, offset: Coordination) -> bool {
+        for block in blocks {
+            let x = block.x + offset.x;
+            let y = block.y + offset.y;
+
+            // Ignore hidden pieces on the top
+            if y < 0 {
+                continue;
+            }
+
+            if y >= R as i16 || x < 0 || x >= C as i16 {
+                return false;
+            }
+
+            if self.inner[y as usize][x as usize].is_occupied() {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Occupied cells of each row as bits, bit `x` is the column `x`.
+    /// The bits above the last column are always set
+    #[cfg(feature = "bitmask-board")]
+    pub fn to_bitboard(&self) -> [u32; R] {
+        core::array::from_fn(|y| self.row_bits(y))
+    }
+
+    #[cfg(feature = "bitmask-board")]
+    fn row_bits(&self, y: usize) -> u32 {
+        let wall = !((1 << C) - 1);
+
+        self.inner[y]
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| cell.is_occupied())
+            .fold(wall, |bits, (x, _)| bits | 1 << x)
+    }
+
+    /// Same as `can_move_in` for a mask made by `BlocksBitmask::to_bitmask_at`,
+    /// each of the 4 rows is checked with a single AND
+    #[cfg(feature = "bitmask-board")]
+    pub fn can_move_in_bitmask(&self, mask: &[u32; 4], offset_y: i16) -> bool {
+        for (i, &row_mask) in mask.iter().enumerate() {
+            let y = offset_y + i as i16;
+
+            // Ignore hidden pieces on the top
+            if row_mask == 0 || y < 0 {
+                continue;
+            }
+
+            if y >= R as i16 || self.bits[y as usize] & row_mask != 0 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Copy of the board with the blocks placed and the full lines cleared,
+    /// together with the number of cleared lines
+    #[cfg(feature = "ai")]
+    pub fn preview_place(
+        &self,
+        blocks: TetrominoBlocks,
+        offset: Coordination,
+        piece: Tetromino,
+    ) -> (Self, u8) {
+        let mut board = self.clone();
+        let (cleared_lines, _) = board.place(blocks, offset, piece);
+        (board, cleared_lines)
+    }
+
+    /// Rows from `start` down to the bottom of the board, empty when `start` is below it
+    pub fn iter_rows_from(&self, start: usize) -> impl Iterator<Item = &[Cell; C]> + '_ {
+        self.inner.iter().skip(start)
+    }
+
+    /// Number of occupied cells in the row, 0 for a row outside of the board
+    pub fn density_at_row(&self, row: usize) -> u8 {
+        self.inner.get(row).map_or(0, |line| {
+            line.iter().filter(|cell| cell.is_occupied()).count() as u8
+        })
+    }
+
+    /// Height of each column, counted from the bottom of the board
+    fn column_heights(&self) -> [usize; C] {
+        core::array::from_fn(|x| {
+            (0..R)
+                .find(|&y| self.inner[y][x].is_occupied())
+                .map_or(0, |y| R - y)
+        })
+    }
+
+    /// Sum of the heights of all the columns
+    pub fn aggregate_height(&self) -> u32 {
+        self.column_heights().iter().sum::<usize>() as u32
+    }
+
+    /// Sum of the height differences between the neighbouring columns
+    fn bumpiness(&self) -> i32 {
+        self.column_heights()
+            .windows(2)
+            .map(|pair| pair[0].abs_diff(pair[1]) as i32)
+            .sum()
+    }
+
+    /// Number of empty cells with an occupied cell somewhere above them
+    fn holes(&self) -> i32 {
+        let heights = self.column_heights();
+        let mut holes = 0;
+
+        for (x, height) in heights.into_iter().enumerate() {
+            for y in (R - height)..R {
+                if !self.inner[y][x].is_occupied() {
+                    holes += 1;
+                }
+            }
+        }
+
+        holes
+    }
+
+    /// Number of sides of the occupied cells not covered by another occupied cell. Everything
+    /// outside of the board counts as exposed, the walls and the floor as well as the space
+    /// above it, so a flat I piece alone on the floor has 10 faces: 4 on top, 4 on the floor
+    /// and its 2 ends
+    pub fn count_surface_area(&self) -> u32 {
+        let mut faces = 0;
+
+        for (Coordination { x, y }, _) in self.iter() {
+            faces += [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                .into_iter()
+                .filter(|&(x, y)| !self.is_occupied(Coordination { x, y }))
+                .count() as u32;
+        }
+
+        faces
+    }
+
+    /// Number of empty cells which can be reached from the top of the board
+    /// by moving through empty cells
+    pub fn accessible_cells(&self) -> u32 {
+        let mut visited = [[false; C]; R];
+        // Flattened stack of `y * C + x`, every cell is pushed at most once
+        let mut stack = [[0u16; C]; R];
+        let mut len = 0;
+        let mut count = 0;
+
+        for (x, cell) in self.inner[0].iter().enumerate() {
+            if !cell.is_occupied() {
+                visited[0][x] = true;
+                stack[0][len] = x as u16;
+                len += 1;
+            }
+        }
+
+        while len > 0 {
+            len -= 1;
+            let index = stack[len / C][len % C] as usize;
+            let (x, y) = (index % C, index / C);
+            count += 1;
+
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+
+            for (x, y) in neighbours {
+                if x < C && y < R && !visited[y][x] && !self.inner[y][x].is_occupied() {
+                    visited[y][x] = true;
+                    stack[len / C][len % C] = (y * C + x) as u16;
+                    len += 1;
+                }
+            }
+        }
+
+        count
+    }
+
+    /// How good the board is to play on, higher is better. Tall stacks, holes, empty cells
+    /// sealed off from the top and uneven columns lower the score. Exposed faces, which give
+    /// the next pieces more places to land, and `cleared_lines` by the piece which led
+    /// to the board raise it
+    pub fn board_quality_score(&self, cleared_lines: u8) -> i64 {
+        let empty = (C * R) as i64 - self.iter().count() as i64;
+        let sealed = empty - self.accessible_cells() as i64;
+
+        -(self.aggregate_height() as i64 * 2
+            + self.holes() as i64 * 4
+            + sealed
+            + self.bumpiness() as i64
+            - self.count_surface_area() as i64 / 4
+            - cleared_lines as i64 * 100)
+    }
+
+    pub fn iter(&self) -> BoardIter<'_, C, R> {
+        BoardIter {
+            board: self,
+            current_coor: Coordination { x: 0, y: 0 },
+        }
+    }
+}
+
+/// Conversion of the blocks of a piece into bits for `Board::can_move_in_bitmask`
+#[cfg(feature = "bitmask-board")]
+pub trait BlocksBitmask {
+    /// Bits of the 4 rows starting at `offset.y`, bit `x` is the column `x` of the board.
+    /// A block outside of the `width` columns sets the whole row, so it collides with the wall
+    fn to_bitmask_at(&self, offset: Coordination, width: usize) -> [u32; 4];
+}
+
+#[cfg(feature = "bitmask-board")]
+impl BlocksBitmask for TetrominoBlocks {
+    fn to_bitmask_at(&self, offset: Coordination, width: usize) -> [u32; 4] {
+        let mut mask = [0; 4];
+
+        for block in self {
+            let x = block.x + offset.x;
+            let row = &mut mask[block.y as usize];
+
+            if x < 0 || x as usize >= width {
+                *row = u32::MAX;
+            } else {
+                *row |= 1 << x;
+            }
+        }
+
+        mask
+    }
+}
+
+impl<const C: usize, const R: usize> fmt::Debug for Board<C, R> {
+    /// Renders the board as a grid, `#` for placed blocks
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in self.inner.iter() {
+            for cell in row {
+                write!(f, "{}", if cell.is_occupied() { '#' } else { '.' })?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct BoardIter<'a, const C: usize, const R: usize> {
+    board: &'a Board<C, R>,
+    current_coor: Coordination,
+}
+
+impl<'a, const COL: usize, const ROW: usize> Iterator for BoardIter<'a, COL, ROW> {
+    type Item = (Coordination, Cell);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut coor = self.current_coor;
+
+        while (coor.x as usize) < COL && (coor.y as usize) < ROW {
+            self.current_coor.x += 1;
+
+            if self.current_coor.x as usize >= COL {
+                self.current_coor.x = 0;
+                self.current_coor.y += 1;
+            }
+
+            let cell = self.board.inner[coor.y as usize][coor.x as usize];
+            if cell.is_occupied() {
+                return Some((coor, cell));
+            }
+
+            coor = self.current_coor;
+        }
+
+        None
+    }
+}
+
+const ALL_TETROMINOES: [Tetromino; 7] = [
+    Tetromino::J,
+    Tetromino::L,
+    Tetromino::S,
+    Tetromino::Z,
+    Tetromino::T,
+    Tetromino::O,
+    Tetromino::I,
+];
+
+/// Number of retries of the `RefillStrategy::History` randomizer
+const HISTORY_RETRIES: u8 = 4;
+/// Number of refills of the queue when the first piece of the game is a bad start
+const FIRST_PIECE_RETRIES: u8 = 3;
+
+/// S and Z leave a hole on an empty board
+fn is_good_first_piece(piece: Tetromino) -> bool {
+    !matches!(piece, Tetromino::S | Tetromino::Z)
+}
+
+/// How the queue generates the upcoming pieces
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum RefillStrategy {
+    /// Every 7 pieces contain each of the tetrominoes exactly once
+    #[default]
+    SevenBag,
+    /// Each piece is picked independently
+    TrulyRandom,
+    /// Rerolls the piece if it is one of the last N pieces (at most 4)
+    History(u8),
+}
+
+#[derive(Clone)]
+pub struct TetrominoQueue {
+    queue: Vec<Tetromino, 7>,
+    strategy: RefillStrategy,
+    history: Vec<Tetromino, 4>,
+}
+
+impl TetrominoQueue {
+    pub fn with_strategy(strategy: RefillStrategy) -> Self {
+        Self {
+            queue: Vec::new(),
+            strategy,
+            history: Vec::new(),
+        }
+    }
+
+    fn init(&mut self, rng: &mut impl Rng) {
+        self.init_filtered(rng, None);
+    }
+
+    /// Fills the queue, refilling it up to `FIRST_PIECE_RETRIES` times while the piece
+    /// coming out first is rejected by `avoid_first`
+    fn init_filtered(&mut self, rng: &mut impl Rng, avoid_first: Option<fn(Tetromino) -> bool>) {
+        self.fill(rng);
+
+        let Some(accept) = avoid_first else {
+            return;
+        };
+
+        for _ in 0..FIRST_PIECE_RETRIES {
+            if self.queue.last().is_some_and(|&piece| accept(piece)) {
+                break;
+            }
+
+            self.queue.clear();
+            self.fill(rng);
+        }
+    }
+
+    fn fill(&mut self, rng: &mut impl Rng) {
+        match self.strategy {
+            RefillStrategy::SevenBag => {
+                let _ = self.queue.extend_from_slice(&ALL_TETROMINOES);
+                self.queue.shuffle(rng);
+            }
+            RefillStrategy::TrulyRandom => {
+                let _ = self.queue.push(random_tetromino(rng));
+            }
+            RefillStrategy::History(n) => {
+                let n = (n as usize).min(self.history.capacity());
+                let recent = &self.history[self.history.len().saturating_sub(n)..];
+                let mut piece = random_tetromino(rng);
+
+                for _ in 0..HISTORY_RETRIES {
+                    if !recent.contains(&piece) {
+                        break;
+                    }
+
+                    piece = random_tetromino(rng);
+                }
+
+                let _ = self.queue.push(piece);
+            }
+        }
+    }
+
+    fn next(&mut self, rng: &mut impl Rng) -> Tetromino {
+        let result = self.queue.pop().unwrap();
+
+        if self.history.is_full() {
+            self.history.remove(0);
+        }
+
+        let _ = self.history.push(result);
+
+        if self.queue.is_empty() {
+            self.init(rng);
+        }
+
+        result
+    }
+
+    pub fn peek(&self) -> Tetromino {
+        *self.queue.last().unwrap()
+    }
+
+    /// Upcoming pieces in the order they will be played, only the ones already drawn
+    /// from the randomizer
+    pub fn iter(&self) -> impl Iterator<Item = Tetromino> + '_ {
+        self.queue.iter().rev().copied()
+    }
+}
+
+/// Gravity of the game in milliseconds per row, computed for each level
+#[derive(Clone)]
+pub struct SpeedCurve {
+    pub base_ms: u64,
+    pub min_ms: u64,
+    pub formula: fn(level: u32, base: u64) -> u64,
+}
+
+/// `(0.8 - (level - 1) * 0.007) ^ (level - 1)` seconds for the levels 1 to 20
+const GUIDELINE_SPEEDS_MS: [u64; 20] = [
+    1000, 793, 618, 473, 355, 262, 190, 135, 94, 64, 43, 28, 18, 11, 7, 4, 3, 1, 1, 0,
+];
+
+impl SpeedCurve {
+    /// Speed of the Tetris guideline, scaled to `base_ms` at level 1 and never faster than 100ms
+    pub const fn guideline() -> Self {
+        Self {
+            base_ms: 1000,
+            min_ms: 100,
+            formula: guideline_formula,
+        }
+    }
+
+    /// Gets 50ms faster every level
+    pub const fn linear(base_ms: u64) -> Self {
+        Self {
+            base_ms,
+            min_ms: 50,
+            formula: linear_formula,
+        }
+    }
+
+    /// Same speed for every level
+    pub const fn constant(ms: u64) -> Self {
+        Self {
+            base_ms: ms,
+            min_ms: ms,
+            formula: constant_formula,
+        }
+    }
+
+    pub fn drop_speed(&self, level: u32) -> u64 {
+        (self.formula)(level, self.base_ms).max(self.min_ms)
+    }
+}
+
+fn guideline_formula(level: u32, base: u64) -> u64 {
+    let index = (level.max(1) as usize - 1).min(GUIDELINE_SPEEDS_MS.len() - 1);
+    GUIDELINE_SPEEDS_MS[index] * base / 1000
+}
+
+fn linear_formula(level: u32, base: u64) -> u64 {
+    base.saturating_sub(50 * level.saturating_sub(1) as u64)
+}
+
+fn constant_formula(_level: u32, base: u64) -> u64 {
+    base
+}
+
+/// Cloning the game makes a checkpoint of it, which plays on independently of the original
+/// when the randomizer can be cloned
+#[derive(Clone)]
+pub struct Tetris<const C: usize, const R: usize, Rng: RngCore> {
+    board: Board<C, R>,
+    state: State,
+    rng: Option<Rng>,
+    cleared_rows: Vec<usize, 4>,
+    /// T-spin of the last placed piece, until it is taken
+    t_spin: Option<TSpin>,
+    /// `Board::checksum` of the board after its last change by the game
+    board_checksum: u16,
+    stats: GameStats,
+    refill_strategy: RefillStrategy,
+    speed_curve: SpeedCurve,
+    /// Interpolate the drop speed between the levels instead of jumping every 10 lines
+    smooth_speed: bool,
+    mode: GameMode,
+    #[cfg(feature = "shadow-mode")]
+    shadow: Option<Shadow<C, R>>,
+}
+
+/// Second game played with the same actions but a different randomizer,
+/// to compare the game feel of the randomizers
+#[cfg(feature = "shadow-mode")]
+#[derive(Clone)]
+pub struct Shadow<const C: usize, const R: usize> {
+    board: Board<C, R>,
+    board_checksum: u16,
+    state: State,
+    cleared_rows: Vec<usize, 4>,
+    t_spin: Option<TSpin>,
+    stats: GameStats,
+    refill_strategy: RefillStrategy,
+}
+
+#[cfg(feature = "shadow-mode")]
+impl<const C: usize, const R: usize> Shadow<C, R> {
+    pub fn board(&self) -> &Board<C, R> {
+        &self.board
+    }
+
+    pub fn game_phase(&self) -> GamePhase {
+        self.state.phase()
+    }
+}
+
+impl<const C: usize, const R: usize, Rng: RngCore> Tetris<C, R, Rng> {
+    pub const fn new() -> Self {
+        Self::with_speed_curve(SpeedCurve::guideline())
+    }
+
+    /// Evaluated at compile time when the game is created
+    const BOARD_SIZE_CHECK: () = {
+        assert!(C >= 4, "board must be at least 4 columns wide");
+        assert!(R >= 8, "board must be at least 8 rows tall");
+        assert!(C * R <= 400, "board too large for stack allocation");
+        assert!(
+            C.is_multiple_of(2),
+            "board width should be even for centered spawning"
+        );
+    };
+
+    pub const fn with_speed_curve(speed_curve: SpeedCurve) -> Self {
+        let () = Self::BOARD_SIZE_CHECK;
+
+        Self {
+            board: Board::new(),
+            state: State::New,
+            rng: None,
+            cleared_rows: Vec::new(),
+            t_spin: None,
+            board_checksum: Board::<C, R>::new().checksum(),
+            stats: GameStats::new(),
+            refill_strategy: RefillStrategy::SevenBag,
+            speed_curve,
+            smooth_speed: false,
+            mode: GameMode::Marathon,
+            #[cfg(feature = "shadow-mode")]
+            shadow: None,
+        }
+    }
+
+    /// Game with the guideline speed, which changes gradually with every cleared line
+    /// when `smooth` is set
+    pub const fn with_smooth_speed(smooth: bool) -> Self {
+        let mut game = Self::new();
+        game.smooth_speed = smooth;
+        game
+    }
+
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = Some(rng);
+    }
+
+    /// Randomizer of the game, for randomness outside of the game itself
+    pub fn rng_mut(&mut self) -> Option<&mut Rng> {
+        self.rng.as_mut()
+    }
+
+    /// Counters of the current game, or of the last one when it is over
+    pub fn stats(&self) -> &GameStats {
+        &self.stats
+    }
+
+    /// Randomizer used for the queue of the next game
+    pub fn set_refill_strategy(&mut self, strategy: RefillStrategy) {
+        self.refill_strategy = strategy;
+    }
+
+    pub fn board(&self) -> &Board<C, R> {
+        &self.board
+    }
+
+    pub fn game_phase(&self) -> GamePhase {
+        self.state.phase()
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Playing(_))
+    }
+
+    /// The board has not changed since the game last changed it
+    pub fn verify_integrity(&self) -> bool {
+        self.board.checksum() == self.board_checksum
+    }
+
+    fn update_checksum(&mut self) {
+        self.board_checksum = self.board.checksum();
+    }
+
+    pub fn playing_state(&self) -> Option<&PlayingState> {
+        match &self.state {
+            State::Playing(ps) => Some(ps),
+            _ => None,
+        }
+    }
+
+    pub fn playing_state_mut(&mut self) -> Option<&mut PlayingState> {
+        match &mut self.state {
+            State::Playing(ps) => Some(ps),
+            _ => None,
+        }
+    }
+
+    /// Starts a new game in the given mode, `now_ms` is the current time for its clock
+    pub fn start_with_mode(&mut self, mode: GameMode, now_ms: u64) {
+        if self.is_playing() || self.rng.is_none() {
+            return;
+        }
+
+        self.mode = mode;
+        self.start_game(now_ms);
+
+        #[cfg(feature = "shadow-mode")]
+        if self.swap_shadow() {
+            self.start_game(now_ms);
+            self.swap_shadow();
+        }
+    }
+
+    /// Mode of the current game, or of the last one when it is over
+    pub fn get_mode(&self) -> GameMode {
+        self.mode
+    }
+
+    /// Lines left to clear in the sprint mode, `None` in the other modes
+    pub fn get_remaining_lines(&self) -> Option<u32> {
+        match self.mode {
+            GameMode::Sprint { lines_goal } => {
+                Some(lines_goal.saturating_sub(self.get_lines_cleared()))
+            }
+            GameMode::Marathon => None,
+        }
+    }
+
+    /// Time in milliseconds it took to finish the sprint, `None` until it is finished
+    /// or when the game was topped out before reaching the goal
+    pub fn get_sprint_time(&self) -> Option<u64> {
+        match &self.state {
+            State::GameOver { time_ms, .. } => *time_ms,
+            _ => None,
+        }
+    }
+
+    fn start_game(&mut self, now_ms: u64) {
+        let mut queue = TetrominoQueue::with_strategy(self.refill_strategy);
+        self.board = Board::new();
+        self.update_checksum();
+        self.stats = GameStats::new();
+        queue.init_filtered(self.rng.as_mut().unwrap(), Some(is_good_first_piece));
+
+        self.state = State::Playing(PlayingState {
+            piece: Tetromino::J,
+            rotation: Rotation::Default,
+            score: 0,
+            lines: 0,
+            consecutive_soft_drops: 0,
+            offset: Coordination { x: 5, y: 0 },
+            queue,
+            held: None,
+            hold_used: false,
+            initial_hold: false,
+            last_drop_ms: now_ms,
+            lock_start_ms: None,
+            lock_resets: 0,
+            last_action_was_rotate: false,
+            combo: -1,
+            last_was_difficult: false,
+            back_to_back: false,
+            start_ms: now_ms,
+            elapsed_ms: 0,
+        });
+
+        self.spawn_new_piece();
+    }
+
+    /// Plays the shadow game next to this one, its pieces are generated by the given strategy
+    #[cfg(feature = "shadow-mode")]
+    pub fn enable_shadow(&mut self, strategy: RefillStrategy) {
+        self.shadow = Some(Shadow {
+            board: Board::new(),
+            board_checksum: Board::<C, R>::new().checksum(),
+            state: State::New,
+            cleared_rows: Vec::new(),
+            t_spin: None,
+            stats: GameStats::new(),
+            refill_strategy: strategy,
+        });
+    }
+
+    #[cfg(feature = "shadow-mode")]
+    pub fn shadow(&self) -> Option<&Shadow<C, R>> {
+        self.shadow.as_ref()
+    }
+
+    /// Applies the action to both this game and its shadow game,
+    /// returns the board update of this game and of the shadow game
+    #[cfg(feature = "shadow-mode")]
+    pub fn run_shadow(&mut self, action: Action) -> (BoardUpdate<16>, BoardUpdate<16>) {
+        let board_update = self.act(action);
+
+        if !self.swap_shadow() {
+            return (board_update, BoardUpdate::None);
+        }
+
+        let shadow_update = self.act(action);
+        self.swap_shadow();
+
+        (board_update, shadow_update)
+    }
+
+    /// Applies the gravity to both this game and its shadow game, each with its own timer,
+    /// returns the board update of this game and of the shadow game
+    #[cfg(feature = "shadow-mode")]
+    pub fn run_shadow_gravity(&mut self, current_ms: u64) -> (BoardUpdate<16>, BoardUpdate<16>) {
+        let board_update = self.gravity_tick(current_ms);
+
+        if !self.swap_shadow() {
+            return (board_update, BoardUpdate::None);
+        }
+
+        let shadow_update = self.gravity_tick(current_ms);
+        self.swap_shadow();
+
+        (board_update, shadow_update)
+    }
+
+    /// Exchanges the game with the shadow game, so the shadow game can reuse the game logic.
+    /// Returns false if there is no shadow game
+    #[cfg(feature = "shadow-mode")]
+    fn swap_shadow(&mut self) -> bool {
+        let Some(shadow) = self.shadow.as_mut() else {
+            return false;
+        };
+
+        core::mem::swap(&mut self.board, &mut shadow.board);
+        core::mem::swap(&mut self.board_checksum, &mut shadow.board_checksum);
+        core::mem::swap(&mut self.state, &mut shadow.state);
+        core::mem::swap(&mut self.cleared_rows, &mut shadow.cleared_rows);
+        core::mem::swap(&mut self.t_spin, &mut shadow.t_spin);
+        core::mem::swap(&mut self.stats, &mut shadow.stats);
+        core::mem::swap(&mut self.refill_strategy, &mut shadow.refill_strategy);
+        true
+    }
+
+    /// Takes the indices of the rows cleared by the last placed piece,
+    /// the indices refer to the board before the rows were removed
+    pub fn take_cleared_rows(&mut self) -> Vec<usize, 4> {
+        core::mem::take(&mut self.cleared_rows)
+    }
+
+    /// Takes the T-spin of the last placed piece, if it made one
+    pub fn take_t_spin(&mut self) -> Option<TSpin> {
+        self.t_spin.take()
+    }
+
+    /// Score of the current game, or of the last one when it is over
+    #[inline]
+    pub fn get_score(&self) -> u64 {
+        match &self.state {
+            State::Playing(ps) => ps.score,
+            State::GameOver { score, .. } => *score,
+            State::New => 0,
+        }
+    }
+
+    #[inline]
+    pub fn get_lines_cleared(&self) -> u32 {
+        self.lines_cleared() as u32
+    }
+
+    #[inline]
+    pub fn get_level(&self) -> u32 {
+        self.level()
+    }
+
+    /// The falling piece
+    pub fn get_piece(&self) -> Option<Tetromino> {
+        self.playing_state().map(|ps| ps.piece)
+    }
+
+    /// Rotation of the falling piece
+    pub fn get_rotation(&self) -> Option<Rotation> {
+        self.playing_state().map(|ps| ps.rotation)
+    }
+
+    /// Offset of the blocks of the falling piece, see `get_live_piece_blocks`
+    pub fn get_offset(&self) -> Option<Coordination> {
+        self.playing_state().map(|ps| ps.offset)
+    }
+
+    /// Queue of the upcoming pieces
+    pub fn get_queue(&self) -> Option<&TetrominoQueue> {
+        self.playing_state().map(|ps| &ps.queue)
+    }
+
+    /// Piece played after the current one
+    pub fn next_piece(&self) -> Option<Tetromino> {
+        self.get_queue().map(TetrominoQueue::peek)
+    }
+
+    /// At most `n` of the upcoming pieces, empty when no game is being played
+    pub fn queue_preview(&self, n: usize) -> impl Iterator<Item = Tetromino> + '_ {
+        self.get_queue()
+            .into_iter()
+            .flat_map(TetrominoQueue::iter)
+            .take(n)
+    }
+
+    /// Number of lines cleared in the current game
+    fn lines_cleared(&self) -> u64 {
+        match &self.state {
+            State::Playing(ps) => ps.lines,
+            State::GameOver { lines, .. } => *lines,
+            State::New => 0,
+        }
+    }
+
+    /// Level of the current game, increases every 10 cleared lines
+    pub fn level(&self) -> u32 {
+        (self.lines_cleared() / 10) as u32 + 1
+    }
+
+    /// Drop speed in milliseconds. With the smooth speed, it moves from the speed of the
+    /// current level towards the next one by a tenth with every cleared line
+    pub fn drop_speed(&self) -> u64 {
+        let level = self.level();
+        let speed = self.speed_curve.drop_speed(level);
+
+        if !self.smooth_speed {
+            return speed;
+        }
+
+        let fraction = self.lines_cleared() % 10;
+        let next_speed = self.speed_curve.drop_speed(level + 1);
+
+        (speed * (10 - fraction) + next_speed * fraction) / 10
+    }
+
+    /// Drops the piece by one row once `drop_speed` milliseconds have passed since its last drop.
+    /// A piece resting on the stack is locked once `LOCK_DELAY_MS` have passed since it landed
+    pub fn gravity_tick(&mut self, current_ms: u64) -> BoardUpdate<16> {
+        let drop_speed = self.drop_speed();
+
+        let resting = self.playing_state().is_some_and(|ps| {
+            let below = Coordination {
+                y: ps.offset.y + 1,
+                ..ps.offset
+            };
+            !self.board.can_move_in(ps.blocks(), below)
+        });
+
+        let Some(ps) = self.playing_state_mut() else {
+            return BoardUpdate::None;
+        };
+
+        ps.elapsed_ms = current_ms.saturating_sub(ps.start_ms);
+
+        if resting {
+            let lock_start_ms = *ps.lock_start_ms.get_or_insert(current_ms);
+            if current_ms.saturating_sub(lock_start_ms) < LOCK_DELAY_MS
+                && ps.lock_resets < MAX_LOCK_RESETS
+            {
+                return BoardUpdate::None;
+            }
+
+            return self.apply(Action::SoftDrop, false);
+        }
+
+        if current_ms.saturating_sub(ps.last_drop_ms) < drop_speed {
+            return BoardUpdate::None;
+        }
+
+        ps.last_drop_ms = current_ms;
+        self.apply(Action::SoftDrop, false)
+    }
+
+    /// Restarts the gravity timer, e.g. after the player dropped the piece by themselves
+    pub fn reset_gravity(&mut self, current_ms: u64) {
+        if let Some(ps) = self.playing_state_mut() {
+            ps.last_drop_ms = current_ms;
+        }
+    }
+
+    /// Deprecated, use `get_live_piece_blocks` instead
+    #[deprecated(note = "use `get_live_piece_blocks` instead")]
+    pub fn get_current_tetromino_position(&self) -> TetrominoBlocks {
+        self.get_live_piece_blocks()
+    }
+
+    /// Blocks of the falling piece on the board
+    pub fn get_live_piece_blocks(&self) -> TetrominoBlocks {
+        let Some(ps) = self.playing_state() else {
+            return [Coordination::default(); 4];
+        };
+
+        ps.blocks().map(|block| Coordination {
+            x: block.x + ps.offset.x,
+            y: block.y + ps.offset.y,
+        })
+    }
+
+    /// Blocks of the falling piece and of its ghost, the place where it would land
+    /// after a hard drop, as `(live, ghost)`
+    pub fn get_overlay_blocks(&self) -> (TetrominoBlocks, TetrominoBlocks) {
+        let live = self.get_live_piece_blocks();
+        (live, self.get_ghost_position().unwrap_or(live))
+    }
+
+    /// Blocks of the falling piece after a hard drop, without dropping it.
+    /// `None` when no game is being played
+    pub fn get_ghost_position(&self) -> Option<TetrominoBlocks> {
+        let ps = self.playing_state()?;
+
+        let blocks = ps.blocks();
+        let mut offset = ps.offset;
+
+        loop {
+            let new_offset = Coordination {
+                y: offset.y + 1,
+                ..offset
+            };
+
+            if !self.board.can_move_in(blocks, new_offset) {
+                break;
+            }
+
+            offset = new_offset;
+        }
+
+        Some(blocks.map(|block| Coordination {
+            x: block.x + offset.x,
+            y: block.y + offset.y,
+        }))
+    }
+
+    /// Blocks of the held piece in its default rotation, relative to `(0, 0)`
+    pub fn get_held_piece_blocks(&self) -> Option<TetrominoBlocks> {
+        let held = self.playing_state()?.held?;
+        Some(get_tetromino_blocks(held, Rotation::Default))
+    }
+
+    fn spawn_new_piece(&mut self) {
+        let State::Playing(ref mut ps) = self.state else {
+            return;
+        };
+
+        let rng = self.rng.as_mut().unwrap();
+        let mut piece = ps.queue.next(rng);
+
+        // The piece goes to the hold before it appears, so it can still save a topped out stack
+        if core::mem::take(&mut ps.initial_hold) {
+            ps.hold_used = true;
+            piece = match ps.held.replace(piece) {
+                Some(held) => held,
+                None => ps.queue.next(rng),
+            };
+        }
+
+        self.spawn_piece(piece);
+    }
+
+    /// Puts the piece at the top of the board, the game is over when it does not fit there
+    fn spawn_piece(&mut self, piece: Tetromino) {
+        let State::Playing(ref mut ps) = self.state else {
+            return;
+        };
+
+        ps.rotation = Rotation::Default;
+        ps.offset = piece.spawn_offset(C);
+        ps.piece = piece;
+        ps.lock_start_ms = None;
+        ps.lock_resets = 0;
+        ps.last_action_was_rotate = false;
+
+        let blocks = ps.blocks();
+        ps.offset.x += self
+            .board
+            .compute_wall_kick_x_offset(blocks, ps.offset)
+            .unwrap_or(0);
+        ps.offset.y += self.board.compute_ceiling_kick_y_offset(blocks, ps.offset);
+
+        if !self.board.can_move_in(ps.blocks(), ps.offset) {
+            self.state = State::GameOver {
+                score: ps.score,
+                lines: ps.lines,
+                time_ms: None,
+            };
+        }
+    }
+
+    pub fn act(&mut self, action: Action) -> BoardUpdate<16> {
+        self.apply(action, true)
+    }
+
+    /// Applies the action, the drops of the player are awarded with points
+    /// while the drops by the gravity are not
+    fn apply(&mut self, action: Action, by_player: bool) -> BoardUpdate<16> {
+        debug_assert!(self.verify_integrity(), "the board has been corrupted");
+
+        let (previous_blocks, previous_ghost) = self.get_overlay_blocks();
+        let level = self.level();
+
+        let State::Playing(ref mut ps) = self.state else {
+            return BoardUpdate::None;
+        };
+
+        let blocks = ps.blocks();
+        let piece = ps.piece;
+
+        // The gravity does not break a row of soft drops
+        if by_player && action != Action::SoftDrop {
+            ps.consecutive_soft_drops = 0;
+        }
+
+        let updated = match action {
+            Action::MoveLeft => {
+                let new_offset = Coordination {
+                    x: ps.offset.x - 1,
+                    ..ps.offset
+                };
+
+                ps.apply_offset_if_valid(new_offset, blocks, &self.board)
+            }
+
+            Action::MoveRight => {
+                let new_offset = Coordination {
+                    x: ps.offset.x + 1,
+                    ..ps.offset
+                };
+
+                ps.apply_offset_if_valid(new_offset, blocks, &self.board)
+            }
+
+            Action::SoftDrop => {
+                let new_offset = Coordination {
+                    y: ps.offset.y + 1,
+                    ..ps.offset
+                };
+
+                if !ps.apply_offset_if_valid(new_offset, blocks, &self.board) {
+                    // The player cannot lock the piece by soft dropping it,
+                    // it is locked by `gravity_tick` after the lock delay
+                    if by_player {
+                        return BoardUpdate::None;
+                    }
+
+                    // The corners are checked before the cleared lines are removed
+                    let t_spin = if piece == Tetromino::T && ps.last_action_was_rotate {
+                        self.board.t_spin(ps.offset, ps.rotation)
+                    } else {
+                        None
+                    };
+
+                    let (cleared_lines, cleared_rows) = self.board.place(blocks, ps.offset, piece);
+                    self.board_checksum = self.board.checksum();
+                    let clear_score = ClearKind::from_lines(cleared_lines)
+                        .map_or(0, |kind| kind.points(level))
+                        + t_spin.map_or(0, |t| t.bonus(cleared_lines, level));
+                    let difficult = cleared_lines == 4 || (cleared_lines > 0 && t_spin.is_some());
+
+                    // Half of the score again for a difficult clear right after another one
+                    ps.back_to_back = difficult && ps.last_was_difficult;
+                    ps.last_was_difficult = difficult;
+                    ps.score += clear_score;
+                    if ps.back_to_back {
+                        ps.score += clear_score / 2;
+                    }
+
+                    if cleared_lines > 0 {
+                        ps.combo += 1;
+                        ps.score += 50 * ps.combo.max(0) as u64 * level as u64;
+                    } else {
+                        ps.combo = -1;
+                    }
+                    self.t_spin = t_spin;
+                    ps.lines += cleared_lines as u64;
+                    ps.consecutive_soft_drops = 0;
+                    ps.hold_used = false;
+                    self.cleared_rows = cleared_rows;
+
+                    self.stats.pieces += 1;
+                    if let Some(kind) = ClearKind::from_lines(cleared_lines) {
+                        self.stats.record_clear(self.stats.pieces, kind);
+                    }
+
+                    // The sprint is over once its lines are cleared, with the time it took
+                    if let GameMode::Sprint { lines_goal } = self.mode {
+                        if ps.lines >= lines_goal as u64 {
+                            self.state = State::GameOver {
+                                score: ps.score,
+                                lines: ps.lines,
+                                time_ms: Some(ps.elapsed_ms),
+                            };
+                            return BoardUpdate::Full;
+                        }
+                    }
+
+                    self.spawn_new_piece();
+                    return BoardUpdate::Full;
+                }
+
+                if by_player {
+                    ps.consecutive_soft_drops = ps.consecutive_soft_drops.saturating_add(1);
+                    ps.score += 1;
+
+                    // An extra point for every soft drop in a row after the first few
+                    if ps.consecutive_soft_drops > SOFT_DROP_BONUS_AFTER {
+                        ps.score += 1;
+                    }
+                }
+
+                true
+            }
+
+            Action::HardDrop => {
+                let start_y = ps.offset.y;
+
+                // increase y offset until it cannot be moved in
+                loop {
+                    let new_offset = Coordination {
+                        y: ps.offset.y + 1,
+                        ..ps.offset
+                    };
+
+                    if !ps.apply_offset_if_valid(new_offset, blocks, &self.board) {
+                        break;
+                    }
+                }
+
+                // Two points for every cell of the hard drop
+                ps.score += 2 * (ps.offset.y - start_y) as u64;
+                if ps.offset.y != start_y {
+                    ps.last_action_was_rotate = false;
+                }
+
+                // let the SoftDrop handle the rest
+                return self.apply(Action::SoftDrop, false);
+            }
+
+            Action::Rotate | Action::RotateCCW => {
+                let new_rotation = if action == Action::Rotate {
+                    ps.rotation.next_cw()
+                } else {
+                    ps.rotation.next_ccw()
+                };
+
+                let blocks = get_tetromino_blocks(ps.piece, new_rotation);
+                let offset = ps.offset;
+
+                // The board grows downwards while the kick tables point up
+                let rotated = ps
+                    .piece
+                    .wall_kick_offsets(ps.rotation, new_rotation)
+                    .iter()
+                    .any(|&(x, y)| {
+                        let kicked = Coordination {
+                            x: offset.x + x,
+                            y: offset.y - y,
+                        };
+                        ps.apply_offset_if_valid(kicked, blocks, &self.board)
+                    });
+                if rotated {
+                    ps.rotation = new_rotation;
+                }
+
+                rotated
+            }
+
+            Action::Hold => {
+                if ps.hold_used {
+                    ps.initial_hold = true;
+                    return BoardUpdate::None;
+                }
+
+                ps.hold_used = true;
+
+                match ps.held.replace(piece) {
+                    Some(held) => self.spawn_piece(held),
+                    None => self.spawn_new_piece(),
+                }
+
+                return BoardUpdate::Full;
+            }
+        };
+
+        if !updated {
+            return BoardUpdate::None;
+        }
+
+        // Moving or rotating a resting piece gives it more time before it is locked
+        if action != Action::SoftDrop && ps.lock_start_ms.take().is_some() {
+            ps.lock_resets = ps.lock_resets.saturating_add(1);
+        }
+
+        ps.last_action_was_rotate = matches!(action, Action::Rotate | Action::RotateCCW);
+
+        let (current_blocks, current_ghost) = self.get_overlay_blocks();
+
+        BoardUpdate::get_partial_update(
+            (previous_blocks, previous_ghost),
+            (current_blocks, current_ghost),
+            piece,
+            Some(&self.board),
+        )
+    }
+}
+
+#[cfg(feature = "async")]
+impl<const C: usize, const R: usize, Rng: RngCore> Tetris<C, R, Rng> {
+    /// Advances the game by one frame, the drops of the gravity up to `current_ms`.
+    /// Asks to be polled again right away, as the game never finishes
+    pub fn poll_frame(&mut self, cx: &mut Context<'_>, current_ms: u64) -> Poll<()> {
+        self.gravity_tick(current_ms);
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
 
-            *piece = queue.next(self.rng.as_mut().unwrap());
+    /// Runs the game as a task of an async executor, `clock` gives the current time
+    /// in milliseconds
+    pub fn into_future(self, clock: fn() -> u64) -> TetrisFuture<C, R, Rng> {
+        TetrisFuture { game: self, clock }
+    }
+}
 
-            if !self
-                .board
-                .can_move_in(get_tetromino_blocks(*piece, *rotation), *offset)
-            {
-                is_gameover = Some(State::GameOver { score });
-            }
-        }
+/// Game running as a future which never resolves, see `Tetris::into_future`
+#[cfg(feature = "async")]
+pub struct TetrisFuture<const C: usize, const R: usize, Rng: RngCore> {
+    game: Tetris<C, R, Rng>,
+    clock: fn() -> u64,
+}
 
-        if let Some(is_gameover) = is_gameover {
-            self.state = is_gameover;
-        }
+#[cfg(feature = "async")]
+impl<const C: usize, const R: usize, Rng: RngCore> TetrisFuture<C, R, Rng> {
+    /// The game, for acting on the inputs between the polls
+    pub fn game_mut(&mut self) -> &mut Tetris<C, R, Rng> {
+        &mut self.game
+    }
+}
+
+#[cfg(feature = "async")]
+impl<const C: usize, const R: usize, Rng: RngCore + Unpin> Future for TetrisFuture<C, R, Rng> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        this.game.poll_frame(cx, (this.clock)())
     }
+}
 
-    pub fn act(&mut self, action: Action) -> BoardUpdate<16> {
-        let previous_blocks = self.get_current_tetromino_position();
-
-        let State::Playing {
-            ref mut piece,
-            ref mut rotation,
-            ref mut offset,
-            ref mut score,
-            ..
-        } = self.state
-        else {
-            return BoardUpdate::None;
+#[cfg(feature = "ai")]
+const ALL_ROTATIONS: [Rotation; 4] = [
+    Rotation::Default,
+    Rotation::Left,
+    Rotation::Flipped,
+    Rotation::Right,
+];
+
+#[cfg(feature = "ai")]
+impl<const C: usize, const R: usize, Rng: RngCore> Tetris<C, R, Rng> {
+    /// First action towards the best placement of the current piece. Every rotation and
+    /// column is dropped straight down from the current height and scored by
+    /// `Board::board_quality_score` of the resulting board. Equally scored placements are decided
+    /// by the number of columns the piece has to move
+    pub fn ai_suggest_action(&self) -> Action {
+        let Some(ps) = self.playing_state() else {
+            return Action::HardDrop;
         };
 
-        let mut board_update = BoardUpdate::None;
-        let mut updated = false;
+        // Score, distance from the current column, rotation and column of the placement
+        let mut best: Option<(i64, i32, Rotation, i16)> = None;
 
-        match action {
-            Action::MoveLeft => {
-                let blocks = get_tetromino_blocks(*piece, *rotation);
-                let mut new_offset = *offset;
-                new_offset.x -= 1;
-
-                if self.board.can_move_in(blocks, new_offset) {
-                    offset.x -= 1;
-                    board_update = BoardUpdate::get_partial_update(
-                        previous_blocks,
-                        self.get_current_tetromino_position(),
-                    );
-                }
-            }
+        for rotation in ALL_ROTATIONS {
+            let blocks = get_tetromino_blocks(ps.piece, rotation);
 
-            Action::MoveRight => {
-                let blocks = get_tetromino_blocks(*piece, *rotation);
-                let mut new_offset = *offset;
-                new_offset.x += 1;
+            for x in -3..C as i16 {
+                let mut offset = Coordination { x, y: ps.offset.y };
 
-                if self.board.can_move_in(blocks, new_offset) {
-                    offset.x += 1;
-                    updated = true;
+                if !self.board.can_move_in(blocks, offset) {
+                    continue;
                 }
-            }
 
-            Action::SoftDrop => {
-                let blocks = get_tetromino_blocks(*piece, *rotation);
-                let mut new_offset = *offset;
-                new_offset.y += 1;
+                // Drop it straight down
+                loop {
+                    let new_offset = Coordination {
+                        y: offset.y + 1,
+                        ..offset
+                    };
 
-                if self.board.can_move_in(blocks, new_offset) {
-                    offset.y += 1;
-                    updated = true;
-                } else {
-                    let cleared_lines = self.board.place(blocks, *offset);
-                    if cleared_lines > 0 {
-                        *score += cleared_lines as u64;
+                    if !self.board.can_move_in(blocks, new_offset) {
+                        break;
                     }
 
-                    self.spawn_new_piece();
-                    return BoardUpdate::Full;
+                    offset = new_offset;
                 }
-            }
 
-            Action::HardDrop => {
-                // increase y offset until it cannot be moved in
-                let blocks = get_tetromino_blocks(*piece, *rotation);
-                let mut new_offset = *offset;
-                new_offset.y += 1;
+                // Blocks left above the board would end the game
+                if blocks.iter().any(|block| block.y + offset.y < 0) {
+                    continue;
+                }
+
+                let (board, cleared_lines) = self.board.preview_place(blocks, offset, ps.piece);
+                let score = board.board_quality_score(cleared_lines);
+
+                let target = Coordination { x, ..ps.offset };
+                let distance = ps.offset.manhattan_distance(&target);
 
-                while self.board.can_move_in(blocks, new_offset) {
-                    new_offset.y += 1;
+                if best.is_none_or(|(best_score, best_distance, _, _)| {
+                    score > best_score || (score == best_score && distance < best_distance)
+                }) {
+                    best = Some((score, distance, rotation, x));
                 }
+            }
+        }
 
-                *offset = new_offset;
-                offset.y -= 1; // undo the last increment
+        let Some((_, _, rotation, x)) = best else {
+            return Action::HardDrop;
+        };
 
-                // let the SoftDrop handle the rest
-                return self.act(Action::SoftDrop);
-            }
-            Action::Rotate => {
-                let new_rotation = match rotation {
-                    Rotation::Default => Rotation::Left,
-                    Rotation::Left => Rotation::Flipped,
-                    Rotation::Flipped => Rotation::Right,
-                    Rotation::Right => Rotation::Default,
-                };
+        if rotation != ps.rotation {
+            Action::Rotate
+        } else if x < ps.offset.x {
+            Action::MoveLeft
+        } else if x > ps.offset.x {
+            Action::MoveRight
+        } else {
+            Action::HardDrop
+        }
+    }
 
-                let blocks = get_tetromino_blocks(*piece, new_rotation);
+    /// Plays `n` ticks of `ms_per_tick` milliseconds by itself, for a reproducible workload
+    /// when the randomizer is seeded. Every tick runs the gravity and one action
+    /// of `ai_suggest_action`, returns the number of lines cleared in the meantime
+    pub fn run_n_ticks(&mut self, n: u32, ms_per_tick: u64) -> u32 {
+        let lines_before = self.get_lines_cleared();
+        let mut current_ms = self.playing_state().map_or(0, |ps| ps.last_drop_ms);
 
-                let mut new_offset = *offset;
-                new_offset.x += self.board.wall_bounce_offset_modifier(blocks, *offset);
+        for _ in 0..n {
+            if !self.is_playing() {
+                break;
+            }
 
-                if self.board.can_move_in(blocks, new_offset) {
-                    *rotation = new_rotation;
-                    *offset = new_offset;
-                    updated = true;
-                }
+            current_ms += ms_per_tick;
+            self.gravity_tick(current_ms);
+
+            if self.is_playing() {
+                self.act(self.ai_suggest_action());
+            }
+        }
+
+        self.get_lines_cleared() - lines_before
+    }
+
+    /// Plays `n` pieces following `ai_suggest_action`, stops early when the game ends or after
+    /// `max_iterations` actions. Returns the score of the game afterwards.
+    /// It runs without a clock, so a blocked piece is hard dropped instead of waiting
+    /// for `gravity_tick` to lock it
+    pub fn simulate_n_pieces(&mut self, n: u32, max_iterations: u32) -> u64 {
+        let mut placed = 0;
+        let mut iterations = 0;
+
+        while placed < n && iterations < max_iterations && self.is_playing() {
+            iterations += 1;
+
+            let update = match self.act(self.ai_suggest_action()) {
+                // The suggested move is blocked, a soft drop of the player never locks the piece
+                BoardUpdate::None => self.act(Action::HardDrop),
+                update => update,
+            };
+
+            // The board is fully redrawn only when a piece has been placed
+            if update.is_full() {
+                placed += 1;
+            }
+        }
+
+        self.get_score()
+    }
+}
+
+impl<const C: usize, const R: usize, Rng: RngCore> Tetris<C, R, Rng> {
+    /// Row of the board in the snapshot of `Debug`, `@` for the live piece, `#` for a placed
+    /// block and `.` for an empty cell
+    fn snapshot_row(&self, y: usize) -> [u8; C] {
+        let current_blocks = self.get_live_piece_blocks();
+
+        core::array::from_fn(|x| {
+            let coord = Coordination {
+                x: x as i16,
+                y: y as i16,
+            };
+
+            if self.is_playing() && current_blocks.contains(&coord) {
+                b'@'
+            } else if self.board.inner[y][x].is_occupied() {
+                b'#'
+            } else {
+                b'.'
+            }
+        })
+    }
+}
+
+impl<const C: usize, const R: usize, Rng: RngCore> fmt::Debug for Tetris<C, R, Rng> {
+    /// Renders the board as a grid, `#` for placed blocks and `@` for the live piece,
+    /// followed by the state of the game
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..R {
+            let row = self.snapshot_row(y);
+            writeln!(f, "{}", core::str::from_utf8(&row).unwrap_or_default())?;
+        }
+
+        match &self.state {
+            State::New => write!(f, "New"),
+            State::Playing(ps) => {
+                write!(f, "Playing score={} next={:?}", ps.score, ps.queue.peek())
             }
+            State::GameOver { score, .. } => write!(f, "GameOver score={}", score),
         }
+    }
+}
 
-        if updated && board_update == BoardUpdate::None {
-            board_update.merge(BoardUpdate::get_partial_update(
-                previous_blocks,
-                self.get_current_tetromino_position(),
-            ));
+/// The same snapshot as `Debug` for the logs of the firmware. The host tests do not link
+/// the global logger of defmt, so it is left out of them
+#[cfg(not(test))]
+impl<const C: usize, const R: usize, Rng: RngCore> defmt::Format for Tetris<C, R, Rng> {
+    fn format(&self, f: defmt::Formatter) {
+        for y in 0..R {
+            let row = self.snapshot_row(y);
+            defmt::write!(
+                f,
+                "{=str}\n",
+                core::str::from_utf8(&row).unwrap_or_default()
+            );
         }
 
-        board_update
+        match &self.state {
+            State::New => defmt::write!(f, "New"),
+            State::Playing(ps) => {
+                defmt::write!(f, "Playing score={=u64} next={}", ps.score, ps.queue.peek())
+            }
+            State::GameOver { score, .. } => defmt::write!(f, "GameOver score={=u64}", *score),
+        }
     }
 }
 
+fn random_tetromino(rng: &mut impl Rng) -> Tetromino {
+    ALL_TETROMINOES[(rng.next_u32() % ALL_TETROMINOES.len() as u32) as usize]
+}
+
 pub fn get_tetromino_blocks(piece: Tetromino, rotation: Rotation) -> TetrominoBlocks {
     let data = match (piece, rotation) {
         (Tetromino::O, _) => [(0, 0), (1, 0), (0, 1), (1, 1)],
 
-        (Tetromino::I, Rotation::Left | Rotation::Right) => [(0, 1), (1, 1), (2, 1), (3, 1)],
-        (Tetromino::I, _) => [(1, 0), (1, 1), (1, 2), (1, 3)],
+        // The I piece shifts within its 4x4 box as it turns, as in SRS
+        (Tetromino::I, Rotation::Default) => [(1, 0), (1, 1), (1, 2), (1, 3)],
+        (Tetromino::I, Rotation::Left) => [(0, 1), (1, 1), (2, 1), (3, 1)],
+        (Tetromino::I, Rotation::Flipped) => [(2, 0), (2, 1), (2, 2), (2, 3)],
+        (Tetromino::I, Rotation::Right) => [(0, 2), (1, 2), (2, 2), (3, 2)],
 
         (Tetromino::S, Rotation::Default) => [(0, 0), (1, 0), (1, 1), (2, 1)],
         (Tetromino::S, Rotation::Left) => [(2, 0), (2, 1), (1, 1), (1, 2)],
@@ -474,28 +2324,162 @@ pub fn get_tetromino_blocks(piece: Tetromino, rotation: Rotation) -> TetrominoBl
     data.map(|v| Coordination { x: v.0, y: v.1 })
 }
 
+#[cfg(feature = "no-heapless-vec")]
+impl<const N: usize> PartialUpdate<N> {
+    /// Evaluated at compile time when the list is created
+    const CAPACITY_CHECK: () = assert!(N <= u8::MAX as usize, "too many cells for a u8 count");
+
+    pub const fn new() -> Self {
+        let () = Self::CAPACITY_CHECK;
+
+        Self {
+            data: [(Coordination { x: 0, y: 0 }, Cell::Empty); N],
+            count: 0,
+        }
+    }
+
+    /// Appends the cell, returns it back if the list is full
+    pub fn push(&mut self, item: (Coordination, Cell)) -> Result<(), (Coordination, Cell)> {
+        let Some(slot) = self.data.get_mut(self.count as usize) else {
+            return Err(item);
+        };
+
+        *slot = item;
+        self.count += 1;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, (Coordination, Cell)> {
+        self.data[..self.len()].iter()
+    }
+
+    pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, (Coordination, Cell)> {
+        let len = self.len();
+        self.data[..len].iter_mut()
+    }
+}
+
+#[cfg(feature = "no-heapless-vec")]
+impl<const N: usize> PartialEq for PartialUpdate<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+#[cfg(feature = "no-heapless-vec")]
+impl<const N: usize> IntoIterator for PartialUpdate<N> {
+    type Item = (Coordination, Cell);
+    type IntoIter = core::iter::Take<core::array::IntoIter<(Coordination, Cell), N>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.data.into_iter().take(self.len())
+    }
+}
+
 impl<const N: usize> BoardUpdate<N> {
-    fn get_partial_update(
-        previous_blocks: TetrominoBlocks,
-        current_blocks: TetrominoBlocks,
+    /// Changes between the previous and current position of the piece and of its ghost,
+    /// both given as `(live, ghost)`. The piece is drawn over its ghost.
+    /// Cells which are occupied on the `board` are never emitted as empty
+    fn get_partial_update<const C: usize, const R: usize>(
+        (previous_blocks, previous_ghost): (TetrominoBlocks, TetrominoBlocks),
+        (current_blocks, current_ghost): (TetrominoBlocks, TetrominoBlocks),
+        piece: Tetromino,
+        board: Option<&Board<C, R>>,
     ) -> Self {
-        let mut list = Vec::new();
+        let mut list = PartialData::new();
+
+        let cell_at = |blocks: &TetrominoBlocks, ghost: &TetrominoBlocks, block| {
+            if blocks.contains(&block) {
+                Cell::Occupied(piece)
+            } else if ghost.contains(&block) {
+                Cell::Ghost
+            } else {
+                Cell::Empty
+            }
+        };
+
+        let previous = previous_blocks.into_iter().chain(
+            previous_ghost
+                .into_iter()
+                .filter(|b| !previous_blocks.contains(b)),
+        );
 
-        for block in previous_blocks {
-            if !current_blocks.contains(&block) {
-                list.push((block, Cell::Empty)).unwrap();
+        for block in previous {
+            if board.is_some_and(|board| board.is_occupied(block)) {
+                continue;
+            }
+
+            let cell = cell_at(&current_blocks, &current_ghost, block);
+            if cell != cell_at(&previous_blocks, &previous_ghost, block) {
+                list.push((block, cell)).unwrap();
             }
         }
 
-        for block in current_blocks {
-            if !previous_blocks.contains(&block) {
-                list.push((block, Cell::Occured)).unwrap();
+        let current = current_blocks.into_iter().chain(
+            current_ghost
+                .into_iter()
+                .filter(|b| !current_blocks.contains(b)),
+        );
+
+        for block in current {
+            if !previous_blocks.contains(&block) && !previous_ghost.contains(&block) {
+                list.push((block, cell_at(&current_blocks, &current_ghost, block)))
+                    .unwrap();
             }
         }
 
         BoardUpdate::Partial(list)
     }
 
+    /// Cells changed by a partial update, nothing for the other updates
+    pub fn iter(&self) -> impl Iterator<Item = &(Coordination, Cell)> {
+        match self {
+            BoardUpdate::Partial(data) => data.iter(),
+            _ => [].iter(),
+        }
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        matches!(self, BoardUpdate::Full)
+    }
+
+    #[inline]
+    pub fn is_partial(&self) -> bool {
+        matches!(self, BoardUpdate::Partial(_))
+    }
+
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        matches!(self, BoardUpdate::None)
+    }
+
+    /// Returns the update and leaves `BoardUpdate::None` in its place
+    #[inline]
+    pub fn take(&mut self) -> Self {
+        core::mem::take(self)
+    }
+
+    /// Number of merges which ran out of capacity for the partial update
+    /// and fell back to a full update
+    pub fn overflow_count() -> u32 {
+        PARTIAL_OVERFLOW_COUNT.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_overflow_count() {
+        PARTIAL_OVERFLOW_COUNT.store(0, Ordering::Relaxed);
+    }
+
     pub fn merge(&mut self, other: Self) {
         let mut require_full_update = false;
 
@@ -516,6 +2500,7 @@ impl<const N: usize> BoardUpdate<N> {
 
                         // Require full update if the vector is completely full
                         if self_data.push(block).is_err() {
+                            PARTIAL_OVERFLOW_COUNT.fetch_add(1, Ordering::Relaxed);
                             require_full_update = true;
                             break;
                         }
@@ -529,3 +2514,475 @@ impl<const N: usize> BoardUpdate<N> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Xorshift generator, the same seed always deals the same pieces
+    struct TestRng(u64);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            (self.next_u64() >> 32) as u32
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                let bytes = self.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    /// Marathon game on the standard board dealt by the given seed
+    fn seeded_game(seed: u64) -> Tetris<10, 20, TestRng> {
+        let mut game = Tetris::new();
+        game.set_rng(TestRng(seed));
+        game.start_with_mode(GameMode::Marathon, 0);
+        game
+    }
+
+    /// Board with the given rows at its bottom, `#` for a block
+    fn board_from_rows(rows: &[&str]) -> Board<10, 20> {
+        let mut board = Board::new();
+        let top = 20 - rows.len();
+
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c == '#' {
+                    board.set_cell(x, top + y, Cell::Occupied(Tetromino::O));
+                }
+            }
+        }
+
+        board
+    }
+
+    /// Game on the board with the piece in the rotation at the offset
+    fn game_with_piece(
+        board: Board<10, 20>,
+        piece: Tetromino,
+        rotation: Rotation,
+        offset: Coordination,
+    ) -> Tetris<10, 20, TestRng> {
+        let mut game = seeded_game(1);
+        game.board = board;
+        game.update_checksum();
+        put_piece(&mut game, piece, rotation, offset);
+        game
+    }
+
+    /// Replaces the falling piece of the game
+    fn put_piece(
+        game: &mut Tetris<10, 20, TestRng>,
+        piece: Tetromino,
+        rotation: Rotation,
+        offset: Coordination,
+    ) {
+        if let State::Playing(ps) = &mut game.state {
+            ps.piece = piece;
+            ps.rotation = rotation;
+            ps.offset = offset;
+        }
+    }
+
+    /// Board with a flat I piece on the floor, its leftmost block in column `x`
+    fn board_with_flat_i<const C: usize, const R: usize>(x: i16) -> Board<C, R> {
+        let mut board = Board::new();
+        let blocks = get_tetromino_blocks(Tetromino::I, Rotation::Left);
+        board.place(blocks, Coordination { x, y: R as i16 - 2 }, Tetromino::I);
+        board
+    }
+
+    #[test]
+    fn mirrored_i_piece_lands_on_the_other_side() {
+        let left = board_with_flat_i::<10, 20>(0);
+        let right = board_with_flat_i::<10, 20>(10 - 4);
+
+        assert_eq!(left.mirror_horizontal().inner, right.inner);
+        assert_eq!(right.mirror_horizontal().inner, left.inner);
+    }
+
+    #[test]
+    fn mirroring_twice_gives_the_original() {
+        let board = board_with_flat_i::<10, 20>(3);
+
+        assert_eq!(board.mirror_vertical().mirror_vertical().inner, board.inner);
+        assert_eq!(board.mirror_vertical().inner[0], board.inner[19]);
+    }
+
+    #[test]
+    fn rotating_four_times_gives_the_original() {
+        let board = board_with_flat_i::<10, 20>(2);
+        let rotated = board.rotate_90().rotate_90().rotate_90().rotate_90();
+
+        assert_eq!(rotated.inner, board.inner);
+        // The bottom row becomes the first column
+        assert!(board.rotate_90().inner[2][0].is_occupied());
+    }
+
+    #[test]
+    fn flat_i_piece_on_the_floor_exposes_ten_faces() {
+        let board = board_with_flat_i::<10, 20>(3);
+
+        assert_eq!(board.count_surface_area(), 10);
+        assert_eq!(board_with_flat_i::<10, 20>(0).count_surface_area(), 10);
+    }
+
+    #[test]
+    fn cells_under_an_overhang_are_not_accessible() {
+        let mut board = Board::<10, 20>::new();
+        assert_eq!(board.accessible_cells(), 10 * 20);
+
+        // Walls in the cell at column 4 of the bottom row
+        board.set_cell(3, 19, Cell::Occupied(Tetromino::O));
+        board.set_cell(5, 19, Cell::Occupied(Tetromino::O));
+        board.set_cell(4, 18, Cell::Occupied(Tetromino::O));
+        assert_eq!(board.accessible_cells(), 10 * 20 - 3 - 1);
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn run_n_ticks_is_reproducible_with_the_same_seed() {
+        let mut first = seeded_game(0x2024);
+        let mut second = seeded_game(0x2024);
+
+        let lines = first.run_n_ticks(400, 50);
+        assert_eq!(second.run_n_ticks(400, 50), lines);
+        assert!(lines > 0);
+        assert_eq!(first.board().inner, second.board().inner);
+        assert_eq!(first.get_score(), second.get_score());
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn simulate_n_pieces_is_reproducible_with_the_same_seed() {
+        let mut first = seeded_game(7);
+        let mut second = seeded_game(7);
+
+        let score = first.simulate_n_pieces(30, 1000);
+        assert_eq!(second.simulate_n_pieces(30, 1000), score);
+        assert_eq!(first.stats().pieces, 30);
+        assert_eq!(first.board().inner, second.board().inner);
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn simulating_no_pieces_keeps_the_score() {
+        let mut game = seeded_game(7);
+        let score = game.simulate_n_pieces(10, 1000);
+        let board = game.board().inner;
+
+        assert_eq!(game.simulate_n_pieces(0, 1000), score);
+        assert_eq!(game.board().inner, board);
+    }
+
+    #[test]
+    fn swapping_the_corners_of_the_board() {
+        let mut board = Board::<10, 20>::new();
+        board.set_cell(0, 0, Cell::Occupied(Tetromino::T));
+
+        assert!(board.swap_cell(0, 0, 9, 19));
+        assert_eq!(board.get_cell(0, 0), Some(Cell::Empty));
+        assert_eq!(board.get_cell(9, 19), Some(Cell::Occupied(Tetromino::T)));
+        assert_eq!(board.iter().count(), 1);
+    }
+
+    #[test]
+    fn swapping_just_outside_of_the_board_changes_nothing() {
+        let mut board = Board::<10, 20>::new();
+        board.set_cell(9, 19, Cell::Occupied(Tetromino::T));
+        let before = board.inner;
+
+        assert!(!board.swap_cell(9, 19, 10, 19));
+        assert!(!board.swap_cell(9, 19, 9, 20));
+        assert!(!board.swap_cell(10, 0, 0, 0));
+        assert_eq!(board.inner, before);
+    }
+
+    #[cfg(feature = "ai")]
+    #[test]
+    fn manhattan_distance_across_the_wall() {
+        let spawn = Coordination { x: 3, y: 0 };
+        let kicked = Coordination { x: -1, y: -2 };
+
+        assert_eq!(spawn.manhattan_distance(&kicked), 6);
+        assert_eq!(kicked.manhattan_distance(&spawn), 6);
+        assert_eq!(kicked.manhattan_distance(&kicked), 0);
+    }
+
+    #[test]
+    fn i_piece_kicks_off_the_left_wall() {
+        // Standing in the first column, lying down in place would stick out of the wall
+        let offset = Coordination { x: -1, y: 10 };
+        let mut game = game_with_piece(Board::new(), Tetromino::I, Rotation::Default, offset);
+
+        game.act(Action::Rotate);
+        let ps = game.playing_state().unwrap();
+        assert!(ps.rotation == Rotation::Left);
+        assert_eq!(ps.offset, Coordination { x: 0, y: 10 });
+    }
+
+    #[test]
+    fn i_piece_kicks_off_the_right_wall() {
+        let offset = Coordination { x: 8, y: 10 };
+        let mut game = game_with_piece(Board::new(), Tetromino::I, Rotation::Default, offset);
+
+        game.act(Action::RotateCCW);
+        let ps = game.playing_state().unwrap();
+        assert!(ps.rotation == Rotation::Right);
+        assert_eq!(ps.offset, Coordination { x: 6, y: 10 });
+    }
+
+    #[test]
+    fn t_spin_triple_uses_the_last_kick() {
+        let board = board_from_rows(&[
+            "###.......",
+            "##........",
+            "##.#######",
+            "##..######",
+            "##.#######",
+        ]);
+        let offset = Coordination { x: 2, y: 15 };
+        let mut game = game_with_piece(board, Tetromino::T, Rotation::Default, offset);
+
+        game.act(Action::Rotate);
+        let ps = game.playing_state().unwrap();
+        // One column left and two rows down, the kick (-1, -2) of the SRS table
+        assert!(ps.rotation == Rotation::Left);
+        assert_eq!(ps.offset, Coordination { x: 1, y: 17 });
+
+        game.act(Action::HardDrop);
+        assert!(game.take_t_spin().is_some());
+        assert_eq!(game.get_lines_cleared(), 3);
+    }
+
+    #[test]
+    fn hold_is_allowed_again_after_the_piece_locks() {
+        let mut game = seeded_game(3);
+
+        assert!(game.act(Action::Hold).is_full());
+        assert!(game.playing_state().unwrap().hold_used);
+
+        game.act(Action::HardDrop);
+        let ps = game.playing_state().unwrap();
+        assert!(!ps.hold_used);
+        assert!(!ps.initial_hold);
+        assert!(game.act(Action::Hold).is_full());
+    }
+
+    #[test]
+    fn second_hold_holds_the_next_piece_as_it_spawns() {
+        let mut game = seeded_game(3);
+        let first = game.get_piece().unwrap();
+
+        game.act(Action::Hold);
+        let next = game.next_piece().unwrap();
+
+        // Not allowed for this piece, kept for the next one
+        assert!(game.act(Action::Hold) == BoardUpdate::None);
+        game.act(Action::HardDrop);
+
+        let ps = game.playing_state().unwrap();
+        assert_eq!(ps.piece, first);
+        assert_eq!(ps.held, Some(next));
+        assert!(ps.hold_used);
+        assert!(!ps.initial_hold);
+    }
+
+    #[test]
+    fn debug_output_shows_the_board_and_the_live_piece() {
+        let mut game: Tetris<4, 8, TestRng> = Tetris::new();
+        game.set_rng(TestRng(5));
+        game.start_with_mode(GameMode::Marathon, 0);
+
+        game.board.set_cell(0, 7, Cell::Occupied(Tetromino::L));
+        game.board.set_cell(1, 7, Cell::Occupied(Tetromino::L));
+        game.board.set_cell(3, 7, Cell::Occupied(Tetromino::I));
+        game.board.set_cell(3, 6, Cell::Occupied(Tetromino::I));
+
+        let next = game.next_piece().unwrap();
+        if let State::Playing(ps) = &mut game.state {
+            ps.piece = Tetromino::O;
+            ps.rotation = Rotation::Default;
+            ps.offset = Coordination { x: 1, y: 2 };
+            ps.score = 120;
+        }
+
+        let expected = format!(
+            "....\n\
+             ....\n\
+             .@@.\n\
+             .@@.\n\
+             ....\n\
+             ....\n\
+             ...#\n\
+             ##.#\n\
+             Playing score=120 next={next:?}"
+        );
+        assert_eq!(format!("{game:?}"), expected);
+    }
+
+    /// Bottom rows of a T-spin double, the slot under the overhang in column 3
+    const T_SPIN_DOUBLE_ROWS: [&str; 3] = ["####......", "###...####", "####.#####"];
+
+    #[test]
+    fn t_slot_with_both_front_corners_is_a_full_t_spin() {
+        let board = board_from_rows(&T_SPIN_DOUBLE_ROWS);
+        let slot = Coordination { x: 3, y: 17 };
+
+        // Pointing down into the slot, both corners under it are filled
+        assert_eq!(board.t_spin(slot, Rotation::Flipped), Some(TSpin::Full));
+        // Pointing up, the overhang is the only filled corner in front of it
+        assert_eq!(board.t_spin(slot, Rotation::Default), Some(TSpin::Mini));
+    }
+
+    #[test]
+    fn two_filled_corners_are_not_a_t_spin() {
+        let board = Board::<10, 20>::new();
+
+        // The floor fills the two corners under the T
+        let on_the_floor = Coordination { x: 3, y: 18 };
+        assert_eq!(board.t_spin(on_the_floor, Rotation::Default), None);
+        assert_eq!(
+            board.t_spin(Coordination { x: 3, y: 5 }, Rotation::Flipped),
+            None
+        );
+    }
+
+    #[test]
+    fn rotating_into_the_slot_scores_a_t_spin_double() {
+        let board = board_from_rows(&T_SPIN_DOUBLE_ROWS);
+        let above = Coordination { x: 3, y: 17 };
+        let mut game = game_with_piece(board, Tetromino::T, Rotation::Left, above);
+
+        game.act(Action::Rotate);
+        game.act(Action::HardDrop);
+
+        assert_eq!(game.take_t_spin(), Some(TSpin::Full));
+        assert_eq!(game.get_lines_cleared(), 2);
+        assert_eq!(game.get_score(), ClearKind::Double.points(1) + 1200);
+    }
+
+    #[test]
+    fn moving_a_resting_piece_restarts_the_lock_delay() {
+        let floor = Coordination { x: 4, y: 18 };
+        let mut game = game_with_piece(Board::new(), Tetromino::O, Rotation::Default, floor);
+
+        game.gravity_tick(1000);
+        game.gravity_tick(1000 + LOCK_DELAY_MS - 1);
+        assert_eq!(game.stats().pieces, 0);
+
+        game.act(Action::MoveLeft);
+        assert_eq!(game.playing_state().unwrap().lock_resets, 1);
+
+        // The delay starts again from the first tick after the move
+        game.gravity_tick(1000 + LOCK_DELAY_MS);
+        assert_eq!(game.stats().pieces, 0);
+        game.gravity_tick(1000 + 2 * LOCK_DELAY_MS);
+        assert_eq!(game.stats().pieces, 1);
+    }
+
+    #[test]
+    fn piece_locks_right_away_after_the_last_reset() {
+        let floor = Coordination { x: 4, y: 18 };
+        let mut game = game_with_piece(Board::new(), Tetromino::O, Rotation::Default, floor);
+
+        for i in 0..MAX_LOCK_RESETS {
+            game.gravity_tick(1000);
+            game.act([Action::MoveLeft, Action::MoveRight][i as usize % 2]);
+        }
+
+        assert_eq!(game.stats().pieces, 0);
+        game.gravity_tick(1000);
+        assert_eq!(game.stats().pieces, 1);
+    }
+
+    #[test]
+    fn back_to_back_tetrises_earn_the_bonus_and_the_combo() {
+        // Eight rows with the first column open, for two Tetrises in a row
+        let board = board_from_rows(&[".#########"; 8]);
+        let top = Coordination { x: -1, y: 0 };
+        let mut game = game_with_piece(board, Tetromino::I, Rotation::Default, top);
+
+        // Two points for each of the 16 rows of the hard drop
+        game.act(Action::HardDrop);
+        let first = 2 * 16 + ClearKind::Tetris.points(1);
+        assert_eq!(game.get_score(), first);
+        assert!(!game.playing_state().unwrap().back_to_back);
+
+        put_piece(&mut game, Tetromino::I, Rotation::Default, top);
+        game.act(Action::HardDrop);
+
+        let ps = game.playing_state().unwrap();
+        assert!(ps.back_to_back);
+        assert_eq!(ps.combo, 1);
+        let second = 2 * 16 + ClearKind::Tetris.points(1) * 3 / 2 + 50;
+        assert_eq!(game.get_score(), first + second);
+    }
+
+    #[test]
+    fn seven_bag_deals_every_piece_once_per_bag() {
+        let mut rng = TestRng(11);
+        let mut queue = TetrominoQueue::with_strategy(RefillStrategy::SevenBag);
+        queue.init(&mut rng);
+
+        for _ in 0..20 {
+            let bag: [Tetromino; 7] = core::array::from_fn(|_| queue.next(&mut rng));
+
+            for piece in ALL_TETROMINOES {
+                assert_eq!(bag.iter().filter(|&&p| p == piece).count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn truly_random_pieces_are_evenly_spread() {
+        let mut rng = TestRng(13);
+        let mut queue = TetrominoQueue::with_strategy(RefillStrategy::TrulyRandom);
+        queue.init(&mut rng);
+
+        let mut counts = [0; 7];
+        for _ in 0..7000 {
+            counts[queue.next(&mut rng) as usize] += 1;
+        }
+
+        // 1000 of each on average
+        for count in counts {
+            assert!((900..=1100).contains(&count), "{counts:?}");
+        }
+    }
+
+    #[test]
+    fn history_rarely_repeats_the_last_piece() {
+        let mut rng = TestRng(17);
+        let mut queue = TetrominoQueue::with_strategy(RefillStrategy::History(4));
+        queue.init(&mut rng);
+
+        let mut last = queue.next(&mut rng);
+        let mut repeats = 0;
+        for _ in 0..7000 {
+            let piece = queue.next(&mut rng);
+            if piece == last {
+                repeats += 1;
+            }
+            last = piece;
+        }
+
+        // A seventh of the pieces would repeat with no history
+        assert!(repeats < 7000 / 7 / 4, "{repeats} repeats");
+    }
+}
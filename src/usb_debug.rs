@@ -0,0 +1,130 @@
+//! Game events sent to the host over a USB serial port, for debugging without a probe.
+//!
+//! The events are written as JSON-like records, one per line, e.g.
+//! `{"event":"line_clear","count":2,"score":600}`
+
+use core::fmt::Write as _;
+use heapless::String;
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::SerialPort;
+
+use crate::hal;
+use crate::tetris::Board;
+
+/// How many times the sending is retried while the USB buffer is full
+const SEND_RETRIES: u8 = 16;
+
+pub type EventRecord = String<64>;
+
+pub struct UsbDebug<'a> {
+    serial: SerialPort<'a, hal::usb::UsbBus>,
+    device: UsbDevice<'a, hal::usb::UsbBus>,
+    last_playing: bool,
+    last_score: u64,
+    last_lines: u64,
+    last_overflow_count: u32,
+}
+
+impl<'a> UsbDebug<'a> {
+    pub fn new(bus: &'a UsbBusAllocator<hal::usb::UsbBus>) -> Self {
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, UsbVidPid(0x16c0, 0x27dd))
+            .strings(&[StringDescriptors::default()
+                .manufacturer("xnguye27")
+                .product("Tetris")
+                .serial_number("IMP2024")])
+            .unwrap()
+            .device_class(usbd_serial::USB_CLASS_CDC)
+            .build();
+
+        Self {
+            serial,
+            device,
+            last_playing: false,
+            last_score: 0,
+            last_lines: 0,
+            last_overflow_count: 0,
+        }
+    }
+
+    /// Must be called regularly to keep the USB connection alive
+    pub fn poll(&mut self) {
+        if self.device.poll(&mut [&mut self.serial]) {
+            // Discard everything sent by the host
+            let mut buf = [0u8; 64];
+            let _ = self.serial.read(&mut buf);
+        }
+    }
+
+    /// Sends the events which happened since the last call
+    pub fn track(&mut self, playing: bool, lines: u64, score: u64) {
+        if playing && !self.last_playing {
+            self.send(&format_event("start", 0, score));
+        } else if playing && lines > self.last_lines {
+            self.send(&format_event(
+                "line_clear",
+                (lines - self.last_lines) as u32,
+                score,
+            ));
+        } else if !playing && self.last_playing {
+            self.send(&format_event("game_over", 0, score));
+        }
+
+        self.last_playing = playing;
+        self.last_score = score;
+        self.last_lines = lines;
+    }
+
+    /// Sends the number of partial board updates which overflowed into a full update
+    /// whenever it changes
+    pub fn track_overflows(&mut self, count: u32) {
+        if count != self.last_overflow_count {
+            self.send(&format_event("partial_overflow", count, self.last_score));
+            self.last_overflow_count = count;
+        }
+    }
+
+    /// Sends the board as a grid, each line prefixed with the label
+    pub fn send_board<const C: usize, const R: usize>(&mut self, label: &str, board: &Board<C, R>) {
+        let mut grid: String<{ 32 * 24 }> = String::new();
+
+        // Boards which do not fit are cut off
+        let _ = write!(&mut grid, "{:?}", board);
+
+        for line in grid.lines() {
+            let mut record = EventRecord::new();
+            let _ = writeln!(&mut record, "{}: {}", label, line);
+            self.send(&record);
+        }
+    }
+
+    fn send(&mut self, record: &EventRecord) {
+        let mut remaining = record.as_bytes();
+        let mut retries = 0;
+
+        // Events are dropped when no host is listening
+        while !remaining.is_empty() && retries < SEND_RETRIES {
+            match self.serial.write(remaining) {
+                Ok(written) => remaining = &remaining[written..],
+                Err(UsbError::WouldBlock) => {
+                    self.device.poll(&mut [&mut self.serial]);
+                    retries += 1;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+pub fn format_event(event: &str, count: u32, score: u64) -> EventRecord {
+    let mut record = EventRecord::new();
+
+    // The longest event fits into the record, the rest is cut off otherwise
+    let _ = writeln!(
+        &mut record,
+        "{{\"event\":\"{}\",\"count\":{},\"score\":{}}}",
+        event, count, score
+    );
+
+    record
+}